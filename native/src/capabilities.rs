@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::{
+    error::{Error, Result},
+    native_messaging::{max_frame_size, max_inbound_frame_size},
+    notifications::Subscribe,
+    Host,
+};
+
+/// Version of the message protocol spoken by this host, bumped whenever
+/// messages change in a way the extension has to know about.
+///
+/// 2: replies bigger than `maxFrameSize` are split into chunks
+/// 3: notifications without rpcId, pushed after `subscribe`
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest protocol version this host still answers to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+pub const METHODS: &[&str] = &["hello", "getCapabilities", "subscribe", "unsubscribe"];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "method")]
+pub enum Message {
+    Hello { content: Hello },
+    GetCapabilities,
+    Subscribe { content: Subscribe },
+    Unsubscribe { content: Subscribe },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hello {
+    pub protocol_version: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub types: BTreeMap<&'static str, &'static [&'static str]>,
+    /// Details handlers give about themselves, by type.
+    pub handlers: BTreeMap<&'static str, Value>,
+    /// Notifications that can be subscribed to.
+    pub notifications: Vec<&'static str>,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    pub max_frame_size: usize,
+    pub max_inbound_frame_size: usize,
+}
+
+impl Capabilities {
+    pub fn new(host: &Host, protocol_version: u32) -> Capabilities {
+        let mut types = BTreeMap::new();
+        types.insert("host", METHODS);
+        types.insert("cancel", &[]);
+        let mut handlers = BTreeMap::new();
+        let mut notifications = Vec::new();
+        for (message_type, handler) in host.handlers() {
+            types.insert(message_type, handler.methods());
+            notifications.extend(handler.notifications());
+            if let Some(capabilities) = handler.capabilities() {
+                handlers.insert(message_type, capabilities);
+            }
+        }
+
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            protocol_version,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            types,
+            handlers,
+            notifications,
+            limits: Limits {
+                max_frame_size: max_frame_size(),
+                max_inbound_frame_size: max_inbound_frame_size(),
+            },
+        }
+    }
+}
+
+pub fn handle_message(host: &Host, message_in: Value) -> Result<Value> {
+    let message: Message = serde_json::from_value(message_in)?;
+    let res = match message {
+        // the extension tells us the newest protocol it speaks and we settle
+        // on the newest one both sides understand
+        Message::Hello { content } => {
+            if content.protocol_version < MIN_PROTOCOL_VERSION {
+                return Err(Error::UnsupportedProtocolVersion(content.protocol_version));
+            }
+            let protocol_version = content.protocol_version.min(PROTOCOL_VERSION);
+            host.set_protocol_version(protocol_version);
+            json!(Capabilities::new(host, protocol_version))
+        }
+        Message::GetCapabilities => json!(Capabilities::new(host, PROTOCOL_VERSION)),
+        Message::Subscribe { content } => {
+            let known = Capabilities::new(host, PROTOCOL_VERSION).notifications;
+            if let Some(unknown) = content
+                .notifications
+                .iter()
+                .find(|notification| !known.contains(&notification.as_str()))
+            {
+                return Err(Error::InvalidMessage(format!(
+                    "unknown notification: {}",
+                    unknown
+                )));
+            }
+            host.notifier().subscribe(content.notifications);
+            json!(host.notifier().subscriptions())
+        }
+        Message::Unsubscribe { content } => {
+            host.notifier().unsubscribe(&content.notifications);
+            json!(host.notifier().subscriptions())
+        }
+    };
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::{SecretBackend, Secrets};
+
+    struct NoSecrets;
+
+    impl SecretBackend for NoSecrets {
+        fn get_password(&self, _service: &str, _account: &str) -> Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn set_password(&self, _service: &str, _account: &str, _password: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn delete_password(&self, _service: &str, _account: &str) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    // unknown methods fail to decode with "unknown variant"
+    fn assert_known(kind: &str, method: &str, result: Result<Value>) {
+        if let Err(Error::Decode(error)) = result {
+            assert!(
+                !error.to_string().contains("unknown variant"),
+                "{} method {} unknown",
+                kind,
+                method
+            );
+        }
+    }
+
+    #[test]
+    fn methods_are_known() {
+        let host = Host::new();
+        for method in METHODS {
+            let result = handle_message(&host, json!({ "method": method }));
+            assert_known("host", method, result);
+        }
+        for method in crate::secrets::METHODS {
+            let result = crate::secrets::handle_message(&NoSecrets, json!({ "method": method }));
+            assert_known("keytar", method, result);
+        }
+        #[cfg(feature = "seshat")]
+        for method in crate::indexer::METHODS {
+            let result =
+                serde_json::from_value::<crate::indexer::Message>(json!({ "method": method }))
+                    .map(|_| json!(null))
+                    .map_err(Error::from);
+            assert_known("seshat", method, result);
+        }
+    }
+
+    #[test]
+    fn hello() {
+        let mut host = Host::new();
+        host.register("keytar", Secrets::new(Box::new(NoSecrets)));
+        let reply = handle_message(
+            &host,
+            json!({
+                "method": "hello",
+                "content": { "protocolVersion": PROTOCOL_VERSION + 1 }
+            }),
+        )
+        .expect("hello");
+        assert_eq!(reply["protocolVersion"], PROTOCOL_VERSION);
+        assert_eq!(reply["types"]["keytar"][0], "getPickleKey");
+        assert_eq!(reply["types"]["cancel"], json!([]));
+        assert_eq!(host.protocol_version(), PROTOCOL_VERSION);
+
+        let error = handle_message(
+            &host,
+            json!({
+                "method": "hello",
+                "content": { "protocolVersion": 0 }
+            }),
+        )
+        .unwrap_err();
+        assert_eq!(error.code(), "unsupportedProtocolVersion");
+    }
+}
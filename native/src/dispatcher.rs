@@ -0,0 +1,391 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    cancel::{Cancel, CancelToken},
+    error::{Error, Result},
+    lock, message_type,
+    native_messaging::{max_inbound_frame_size, FrameReader, FrameWriter},
+    Host,
+};
+
+struct Job {
+    rpc_id: i64,
+    message: Value,
+    cancel: CancelToken,
+}
+
+#[derive(Deserialize)]
+struct CancelMessage {
+    content: Cancel,
+}
+
+/// Requests that were dispatched but didn't reply yet, by rpcId.
+type Pending = Arc<Mutex<HashMap<i64, CancelToken>>>;
+
+struct Lane {
+    sender: Sender<Job>,
+    handle: JoinHandle<()>,
+}
+
+/// Runs incoming requests on worker threads, one lane per [`Host::lane`], so
+/// e.g. requests for the same event store keep their order while a slow search
+/// doesn't hold up unrelated requests.
+pub struct Dispatcher {
+    host: Arc<Host>,
+    writer: Arc<FrameWriter>,
+    lanes: HashMap<String, Lane>,
+    pending: Pending,
+}
+
+impl Dispatcher {
+    pub fn new(host: Host, writer: Arc<FrameWriter>) -> Dispatcher {
+        Dispatcher {
+            host: Arc::new(host),
+            writer,
+            lanes: HashMap::new(),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn dispatch(&mut self, rpc_id: i64, message: Value) {
+        let lane = match self.host.lane(&message) {
+            Ok(lane) => lane,
+            Err(error) => {
+                self.writer.error(rpc_id, error);
+                return;
+            }
+        };
+
+        let cancel = CancelToken::default();
+        lock(&self.pending).insert(rpc_id, cancel.clone());
+
+        let host = self.host.clone();
+        let writer = self.writer.clone();
+        let pending = self.pending.clone();
+        let lane = self
+            .lanes
+            .entry(lane)
+            .or_insert_with(|| Dispatcher::spawn_lane(host, writer, pending));
+
+        let job = Job {
+            rpc_id,
+            message,
+            cancel,
+        };
+        if let Err(error) = lane.sender.send(job) {
+            lock(&self.pending).remove(&rpc_id);
+            let error = Error::Internal(format!("worker gone: {}", error));
+            self.writer.error(rpc_id, error);
+        }
+    }
+
+    /// Lets every lane finish the requests it already received, then flushes
+    /// and closes all event stores that are still open.
+    pub fn shutdown(self) -> Result<()> {
+        for (_, lane) in self.lanes {
+            drop(lane.sender);
+            if lane.handle.join().is_err() {
+                eprintln!("worker panicked during shutdown");
+            }
+        }
+
+        self.host.shutdown()
+    }
+
+    fn spawn_lane(host: Arc<Host>, writer: Arc<FrameWriter>, pending: Pending) -> Lane {
+        let (sender, receiver) = channel::<Job>();
+
+        let handle = thread::spawn(move || {
+            for job in receiver {
+                let Job {
+                    rpc_id,
+                    message,
+                    cancel,
+                } = job;
+
+                let reply = match cancel.check() {
+                    Err(error) => Err(error),
+                    Ok(()) if message_type(&message).ok() == Some("cancel") => {
+                        cancel_request(&pending, message)
+                    }
+                    Ok(()) => host.handle_message(message, &cancel),
+                };
+                lock(&pending).remove(&rpc_id);
+                // hello might have settled on another protocol version
+                writer.set_protocol_version(host.protocol_version());
+
+                match reply {
+                    Ok(reply) => writer.reply(rpc_id, reply),
+                    Err(error) => writer.error(rpc_id, error),
+                };
+            }
+        });
+
+        Lane { sender, handle }
+    }
+}
+
+/// Serves one connection until the other side closes it or reading from it
+/// fails, then shuts down all event stores that were opened through it.
+///
+/// A broken or garbled stream is returned as error once the event stores are
+/// shut down, so that it can be told apart from the other side closing it.
+pub fn serve(reader: impl Read, writer: impl Write + Send + 'static) -> Result<()> {
+    let writer = Arc::new(FrameWriter::new(writer));
+    let mut reader = FrameReader::new(reader, max_inbound_frame_size());
+    let host = Host::default();
+    let sink = writer.clone();
+    host.notifier()
+        .set_sink(move |notification, params| sink.notify(notification, params));
+    let mut dispatcher = Dispatcher::new(host, writer.clone());
+
+    writer.ready();
+    let mut broken = None;
+    loop {
+        let (rpc_id, message) = match reader.read_message() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            // e.g. a reset socket, nothing more is going to come from it
+            Err(error) => {
+                broken = Some(error);
+                break;
+            }
+        };
+
+        match message {
+            Ok(message) => dispatcher.dispatch(rpc_id, message),
+            Err(error) => writer.error(rpc_id, error),
+        }
+    }
+
+    dispatcher.shutdown()?;
+    match broken {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Cancels the request with the given rpcId, replying whether it was still
+/// pending.
+fn cancel_request(pending: &Pending, message: Value) -> Result<Value> {
+    let CancelMessage { content } = serde_json::from_value(message)?;
+    let cancelled = lock(pending).get(&content.rpc_id).map(CancelToken::cancel);
+    Ok(json!(cancelled.is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+    use std::{
+        io::{self, Cursor},
+        sync::mpsc::{Receiver, RecvTimeoutError},
+        time::Duration,
+    };
+
+    use crate::handler::Handler;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Hands every frame written to it to the test.
+    struct Frames {
+        sender: Sender<Value>,
+        buffer: Vec<u8>,
+    }
+
+    impl Write for Frames {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let mut frames = Cursor::new(std::mem::take(&mut self.buffer));
+            while let Ok(size) = frames.read_u32::<NativeEndian>() {
+                let mut frame = vec![0; size as usize];
+                frames.read_exact(&mut frame)?;
+                let frame = serde_json::from_slice(&frame).expect("frame");
+                self.sender.send(frame).expect("test gone");
+            }
+            Ok(())
+        }
+    }
+
+    /// Replies with the message's `lane`. `block` waits for the test to
+    /// release it, `wait` runs until it's cancelled.
+    struct Stub {
+        started: Mutex<Sender<i64>>,
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl Handler for Stub {
+        fn methods(&self) -> &'static [&'static str] {
+            &["block", "wait", "echo"]
+        }
+
+        fn lane(&self, message: &Value) -> Result<Option<String>> {
+            Ok(message["lane"].as_str().map(str::to_owned))
+        }
+
+        fn handle(&self, message: Value, cancel: &CancelToken) -> Result<Value> {
+            let rpc_id = message["rpcId"].as_i64().unwrap_or_default();
+            self.started.lock().unwrap().send(rpc_id).unwrap();
+            if message["method"] == "block" {
+                self.release.lock().unwrap().recv().unwrap();
+            }
+            if message["method"] == "wait" {
+                while !cancel.is_cancelled() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                cancel.check()?;
+            }
+            Ok(message["lane"].clone())
+        }
+    }
+
+    struct Test {
+        dispatcher: Dispatcher,
+        frames: Receiver<Value>,
+        started: Receiver<i64>,
+        release: Sender<()>,
+    }
+
+    impl Test {
+        fn new() -> Test {
+            let (started, started_receiver) = channel();
+            let (release_sender, release) = channel();
+            let mut host = Host::new();
+            host.register(
+                "stub",
+                Stub {
+                    started: Mutex::new(started),
+                    release: Mutex::new(release),
+                },
+            );
+            let (sender, frames) = channel();
+            let writer = Arc::new(FrameWriter::new(Frames {
+                sender,
+                buffer: Vec::new(),
+            }));
+            Test {
+                dispatcher: Dispatcher::new(host, writer),
+                frames,
+                started: started_receiver,
+                release: release_sender,
+            }
+        }
+
+        fn send(&mut self, rpc_id: i64, mut message: Value) {
+            message["rpcId"] = json!(rpc_id);
+            self.dispatcher.dispatch(rpc_id, message);
+        }
+
+        fn receive(&self) -> Value {
+            self.frames.recv_timeout(TIMEOUT).expect("no reply")
+        }
+    }
+
+    /// A stream that broke, after handing out `data`.
+    struct Reset(Cursor<Vec<u8>>);
+
+    impl Read for Reset {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::ConnectionReset.into()),
+                read => Ok(read),
+            }
+        }
+    }
+
+    #[test]
+    fn connection_reset() {
+        // in between frames and in the middle of one
+        let mut frame = Vec::new();
+        frame.write_u32::<NativeEndian>(100).unwrap();
+        frame.push(b'{');
+        for stream in [Vec::new(), frame].iter() {
+            let (sender, frames) = channel();
+            let writer = Frames {
+                sender,
+                buffer: Vec::new(),
+            };
+            let (done, served) = channel();
+            let reader = Reset(Cursor::new(stream.clone()));
+            thread::spawn(move || done.send(serve(reader, writer).err().map(|error| error.code())));
+
+            assert_eq!(served.recv_timeout(TIMEOUT), Ok(Some("ioError")));
+            assert_eq!(frames.recv().expect("ready")["ready"], true);
+            assert!(frames.try_recv().is_err());
+        }
+    }
+
+    #[test]
+    fn lanes() {
+        let mut test = Test::new();
+        test.send(1, json!({"type": "stub", "method": "block", "lane": "a"}));
+        assert_eq!(test.started.recv_timeout(TIMEOUT), Ok(1));
+        test.send(2, json!({"type": "stub", "method": "echo", "lane": "a"}));
+        test.send(3, json!({"type": "stub", "method": "echo", "lane": "b"}));
+
+        // lane b doesn't wait for the blocked request on lane a
+        let reply = test.receive();
+        assert_eq!(reply["rpcId"], 3);
+        assert_eq!(reply["reply"], "b");
+        assert_eq!(test.started.recv_timeout(TIMEOUT), Ok(3));
+        assert_eq!(
+            test.started.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+
+        // requests on the same lane keep their order
+        test.release.send(()).unwrap();
+        assert_eq!(test.receive()["rpcId"], 1);
+        assert_eq!(test.receive()["rpcId"], 2);
+        test.dispatcher.shutdown().expect("shutdown");
+    }
+
+    #[test]
+    fn cancel() {
+        let mut test = Test::new();
+        test.send(1, json!({"type": "stub", "method": "block", "lane": "a"}));
+        assert_eq!(test.started.recv_timeout(TIMEOUT), Ok(1));
+        test.send(2, json!({"type": "stub", "method": "echo", "lane": "a"}));
+
+        // queued requests are dropped without running
+        test.send(3, json!({"type": "cancel", "content": {"rpcId": 2}}));
+        assert_eq!(test.receive(), json!({"rpcId": 3, "reply": true}));
+        test.release.send(()).unwrap();
+        assert_eq!(test.receive()["rpcId"], 1);
+        let reply = test.receive();
+        assert_eq!(reply["rpcId"], 2);
+        assert_eq!(reply["error"]["code"], "cancelled");
+
+        // running requests are told to stop
+        test.send(4, json!({"type": "stub", "method": "wait", "lane": "b"}));
+        assert_eq!(test.started.recv_timeout(TIMEOUT), Ok(4));
+        test.send(5, json!({"type": "cancel", "content": {"rpcId": 4}}));
+        assert_eq!(test.receive(), json!({"rpcId": 5, "reply": true}));
+        let reply = test.receive();
+        assert_eq!(reply["rpcId"], 4);
+        assert_eq!(reply["error"]["code"], "cancelled");
+
+        // nothing left to cancel
+        test.send(6, json!({"type": "cancel", "content": {"rpcId": 4}}));
+        assert_eq!(test.receive(), json!({"rpcId": 6, "reply": false}));
+        assert_eq!(
+            test.started.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        test.dispatcher.shutdown().expect("shutdown");
+    }
+}
@@ -0,0 +1,184 @@
+use serde_json::{json, Value};
+#[cfg(feature = "seshat")]
+use seshat::Error as SeshatError;
+use std::{fmt, io, sync::mpsc::RecvError};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors that are replied to the extension as `{code, message, data}`.
+///
+/// The code is stable and meant to be matched on by the web side, the message
+/// is for humans and data carries optional details.
+#[derive(Debug)]
+pub enum Error {
+    /// The message was missing required fields or had them in the wrong shape.
+    InvalidMessage(String),
+    /// A frame from the extension exceeded the inbound size limit.
+    FrameTooLarge(usize),
+    /// The message couldn't be decoded, e.g. unknown type or method.
+    Decode(serde_json::Error),
+    /// The extension only speaks protocol versions this host dropped.
+    UnsupportedProtocolVersion(u32),
+    /// No handler is registered for the message `type`, e.g. because it
+    /// wasn't compiled in.
+    UnknownType(String),
+    /// The search term doesn't follow the query syntax, `position` is the
+    /// character where the problem starts.
+    InvalidQuery {
+        position: usize,
+        message: String,
+    },
+    /// `initEventIndex` wasn't called for the event store yet.
+    IndexNotInitialized,
+    #[cfg(feature = "seshat")]
+    Seshat(SeshatError),
+    /// The event store is being reindexed in the background.
+    ReindexInProgress {
+        reindexed: u64,
+        total: u64,
+    },
+    /// The OS keyring has to be unlocked by the user first.
+    KeyringLocked(String),
+    Keyring(String),
+    /// The extension cancelled the request before it finished.
+    Cancelled,
+    /// The reply exceeds the frame size limit and the extension can't
+    /// reassemble chunked replies.
+    ReplyTooLarge(usize),
+    Io(io::Error),
+    Internal(String),
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidMessage(_) => "invalidMessage",
+            Error::FrameTooLarge(_) => "frameTooLarge",
+            Error::Decode(_) => "decodeError",
+            Error::UnsupportedProtocolVersion(_) => "unsupportedProtocolVersion",
+            Error::UnknownType(_) => "unknownType",
+            Error::InvalidQuery { .. } => "invalidQuery",
+            Error::IndexNotInitialized => "indexNotInitialized",
+            #[cfg(feature = "seshat")]
+            Error::Seshat(SeshatError::ReindexError) => "reindexRequired",
+            #[cfg(feature = "seshat")]
+            Error::Seshat(SeshatError::DatabaseUnlockError(_)) => "databaseUnlockFailed",
+            #[cfg(feature = "seshat")]
+            Error::Seshat(SeshatError::DatabaseVersionError) => "databaseVersionMismatch",
+            #[cfg(feature = "seshat")]
+            Error::Seshat(_) => "seshatError",
+            Error::ReindexInProgress { .. } => "reindexInProgress",
+            Error::KeyringLocked(_) => "keyringLocked",
+            Error::Keyring(_) => "keyringError",
+            Error::Cancelled => "cancelled",
+            Error::ReplyTooLarge(_) => "replyTooLarge",
+            Error::Io(_) => "ioError",
+            Error::Internal(_) => "internalError",
+        }
+    }
+
+    pub fn data(&self) -> Value {
+        match self {
+            #[cfg(feature = "seshat")]
+            Error::Seshat(error) => json!(format!("{:?}", error)),
+            Error::InvalidQuery { position, .. } => json!({ "position": position }),
+            Error::ReindexInProgress { reindexed, total } => json!({
+                "reindexed": reindexed,
+                "total": total,
+            }),
+            Error::Io(error) => json!(format!("{:?}", error.kind())),
+            _ => json!(null),
+        }
+    }
+
+    pub fn to_reply(&self) -> Value {
+        json!({
+            "code": self.code(),
+            "message": self.to_string(),
+            "data": self.data(),
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidMessage(message) => write!(f, "invalid message: {}", message),
+            Error::FrameTooLarge(size) => write!(f, "frame too large: {} bytes", size),
+            Error::Decode(error) => write!(f, "decoding message failed: {}", error),
+            Error::UnsupportedProtocolVersion(version) => {
+                write!(f, "unsupported protocol version: {}", version)
+            }
+            Error::UnknownType(message_type) => write!(f, "unknown type: {}", message_type),
+            Error::InvalidQuery { position, message } => {
+                write!(f, "invalid query at character {}: {}", position, message)
+            }
+            Error::IndexNotInitialized => write!(f, "index not initialized"),
+            #[cfg(feature = "seshat")]
+            Error::Seshat(error) => write!(f, "{}", error),
+            Error::ReindexInProgress { reindexed, total } => {
+                write!(f, "reindex in progress: {}/{} events", reindexed, total)
+            }
+            Error::KeyringLocked(message) => write!(f, "keyring locked: {}", message),
+            Error::Keyring(message) => write!(f, "keyring error: {}", message),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::ReplyTooLarge(size) => write!(f, "reply too large: {} bytes", size),
+            Error::Io(error) => write!(f, "{}", error),
+            Error::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Decode(error)
+    }
+}
+
+#[cfg(feature = "seshat")]
+impl From<SeshatError> for Error {
+    fn from(error: SeshatError) -> Self {
+        Error::Seshat(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<RecvError> for Error {
+    fn from(_: RecvError) -> Self {
+        Error::Internal("seshat writer thread gone".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply() {
+        assert_eq!(
+            Error::IndexNotInitialized.to_reply(),
+            json!({
+                "code": "indexNotInitialized",
+                "message": "index not initialized",
+                "data": null,
+            })
+        );
+
+        #[cfg(feature = "seshat")]
+        {
+            let reply = Error::from(SeshatError::ReindexError).to_reply();
+            assert_eq!(reply["code"], "reindexRequired");
+            assert_eq!(reply["data"], "ReindexError");
+        }
+
+        let error = serde_json::from_str::<Value>("{").unwrap_err();
+        assert_eq!(Error::from(error).to_reply()["code"], "decodeError");
+    }
+}
@@ -0,0 +1,34 @@
+use serde_json::Value;
+
+use crate::{cancel::CancelToken, error::Result};
+
+/// A subsystem answering the messages of one `type`, registered with
+/// [`Host::register`](crate::Host::register).
+pub trait Handler: Send + Sync {
+    /// Methods announced in `getCapabilities`.
+    fn methods(&self) -> &'static [&'static str];
+
+    /// Extra details announced in `getCapabilities`, e.g. supported options.
+    fn capabilities(&self) -> Option<Value> {
+        None
+    }
+
+    /// Notifications the handler might push, announced in `getCapabilities`
+    /// and accepted by `subscribe`.
+    fn notifications(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Requests on the same lane run in order, different lanes run
+    /// concurrently. By default all requests of a type share one lane.
+    fn lane(&self, _message: &Value) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn handle(&self, message: Value, cancel: &CancelToken) -> Result<Value>;
+
+    /// Called once the client went away, after all its requests finished.
+    fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+}
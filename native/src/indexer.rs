@@ -1,13 +1,62 @@
-use anyhow::{bail, Context, Result};
+mod edits;
+pub mod filter;
+pub mod highlight;
+mod html;
+mod query;
+mod redactions;
+mod replies;
+
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use seshat::{
     Config, Connection, CrawlerCheckpoint, Database, Error as SeshatError, EventType, Language,
-    LoadConfig, Profile, RecoveryDatabase, SearchConfig,
+    LoadConfig, Profile, RecoveryDatabase, SearchConfig, Searcher,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
 };
-use std::{collections::HashMap, path::PathBuf};
 
-use crate::Radical;
+use crate::{
+    cancel::CancelToken,
+    error::{Error, Result},
+    handler::Handler,
+    lock,
+    notifications::Notifier,
+};
+use edits::Edited;
+use filter::{FileFilter, SearchFilter};
+use highlight::{Highlighter, Snippet};
+use query::Query;
+
+pub const METHODS: &[&str] = &[
+    "initEventIndex",
+    "loadCheckpoints",
+    "isEventIndexEmpty",
+    "isRoomIndexed",
+    "commitLiveEvents",
+    "addEventToIndex",
+    "addCrawlerCheckpoint",
+    "addHistoricEvents",
+    "removeCrawlerCheckpoint",
+    "searchEventIndex",
+    "loadFileEvents",
+    "getUserVersion",
+    "setUserVersion",
+    "deleteEvent",
+    "getStats",
+    "closeEventIndex",
+    "deleteEventIndex",
+    "reindexEventIndex",
+    "setIndexLanguage",
+    "searchEventIndexes",
+];
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase", tag = "method")]
@@ -15,26 +64,69 @@ pub enum Message {
     InitEventIndex(InitEventIndex),
     LoadCheckpoints,
     IsEventIndexEmpty,
-    IsRoomIndexed { content: IsRoomIndexed },
+    IsRoomIndexed {
+        content: IsRoomIndexed,
+    },
     CommitLiveEvents,
-    AddEventToIndex { content: AddEventToIndex },
-    AddCrawlerCheckpoint { content: AddHistoricEvents },
-    AddHistoricEvents { content: AddHistoricEvents },
-    RemoveCrawlerCheckpoint { content: AddHistoricEvents },
-    SearchEventIndex { content: SearchEventIndex },
-    LoadFileEvents { content: LoadConfig },
+    AddEventToIndex {
+        content: AddEventToIndex,
+    },
+    AddCrawlerCheckpoint {
+        content: AddHistoricEvents,
+    },
+    AddHistoricEvents {
+        content: AddHistoricEvents,
+    },
+    RemoveCrawlerCheckpoint {
+        content: AddHistoricEvents,
+    },
+    SearchEventIndex {
+        content: SearchEventIndex,
+    },
+    LoadFileEvents {
+        content: LoadFileEvents,
+    },
     GetUserVersion,
-    SetUserVersion { content: SetUserVersion },
-    DeleteEvent { content: DeleteEvent },
+    SetUserVersion {
+        content: SetUserVersion,
+    },
+    DeleteEvent {
+        content: DeleteEvent,
+    },
     GetStats,
     CloseEventIndex,
     DeleteEventIndex,
+    ReindexEventIndex {
+        #[serde(default)]
+        content: ReindexEventIndex,
+    },
+    SetIndexLanguage {
+        content: SetIndexLanguage,
+    },
+    SearchEventIndexes {
+        content: SearchEventIndexes,
+    },
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct InitEventIndex {
     pub passphrase: Option<String>,
     pub language: Option<String>,
+    /// Index the text of `formatted_body` instead of `body` where there is
+    /// one. Sticks with the event store once given, changing it rebuilds the
+    /// index like `setIndexLanguage` does.
+    pub formatted_body: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReindexEventIndex {
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetIndexLanguage {
+    pub language: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -58,9 +150,77 @@ pub struct AddHistoricEvents {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SearchEventIndex {
     pub term: String,
     pub config: SearchConfig,
+    /// Characters of `content.body` around the matches, no snippets without.
+    pub snippet_width: Option<usize>,
+    pub filter: Option<SearchFilter>,
+    /// `next_batch` of the previous page, instead of the one in `config`
+    /// which only takes seshat's tokens.
+    pub next_batch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchEventIndexes {
+    /// All open event stores if not given.
+    pub event_stores: Option<Vec<String>>,
+    pub term: String,
+    pub config: SearchConfig,
+    pub snippet_width: Option<usize>,
+    pub filter: Option<SearchFilter>,
+    /// `next_batch` of the previous page, which knows the event stores.
+    pub next_batch: Option<String>,
+}
+
+/// `next_batch` of searches across event stores.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct SearchBatch {
+    cursors: BTreeMap<String, SearchCursor>,
+    /// Results in the event stores that were searched through already.
+    done: usize,
+}
+
+/// Where the search in one event store continues: seshat's token of the batch
+/// that wasn't used up, and how many results of it were already returned.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct SearchCursor {
+    token: Option<String>,
+    skip: usize,
+}
+
+/// `next_batch` of filtered searches, with the number of matching results
+/// that was counted for the first page.
+#[derive(Debug, Deserialize, Serialize)]
+struct FilteredBatch {
+    #[serde(flatten)]
+    cursor: SearchCursor,
+    count: usize,
+}
+
+impl FilteredBatch {
+    fn encode(&self) -> Result<String> {
+        Ok(base64::encode(serde_json::to_vec(self)?))
+    }
+}
+
+/// Count, results, `next_batch` of a filtered search and the one it started
+/// from.
+type FilteredPage = (
+    usize,
+    Vec<seshat::SearchResult>,
+    Option<String>,
+    Option<String>,
+);
+
+#[derive(Debug, Deserialize)]
+pub struct LoadFileEvents {
+    #[serde(flatten)]
+    pub config: LoadConfig,
+    #[serde(flatten)]
+    pub filter: FileFilter,
 }
 
 #[derive(Debug, Deserialize)]
@@ -79,8 +239,12 @@ pub struct SetUserVersion {
 pub struct SearchResults {
     pub count: usize,
     pub results: Vec<SearchResult>,
-    pub highlights: Vec<SearchHighlight>,
+    pub highlights: Vec<String>,
     pub next_batch: Option<String>,
+    /// `next_batch` this page started from, with anything that was worked
+    /// out for it, to come back to when the page wasn't used up.
+    #[serde(skip)]
+    batch: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -88,6 +252,11 @@ pub struct SearchResult {
     pub rank: f32,
     pub result: Value,
     pub context: SearchResultContext,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snippets: Option<Vec<Snippet>>,
+    /// Set when searching several event stores.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_store: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -97,9 +266,6 @@ pub struct SearchResultContext {
     pub profile_info: HashMap<String, Profile>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SearchHighlight {}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileEvent {
     pub event: Value,
@@ -137,10 +303,47 @@ pub struct EventMessage {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EventMessageContent {
+    /// The caption of files that have a `filename`, their name otherwise.
     pub body: String,
     pub msgtype: Option<String>,
+    pub filename: Option<String>,
+    pub format: Option<String>,
+    pub formatted_body: Option<String>,
+    #[serde(rename = "m.relates_to")]
+    pub relates_to: Option<Value>,
+}
+
+impl EventMessageContent {
+    /// The text that gets indexed.
+    fn text(self, formatted_body: bool) -> String {
+        let mut text = match self.formatted_body {
+            Some(html) if formatted_body && self.format.as_deref() == Some(HTML_FORMAT) => {
+                html::to_text(&html)
+            }
+            _ => String::new(),
+        };
+        if text.is_empty() {
+            let in_reply_to = self
+                .relates_to
+                .as_ref()
+                .map(|relation| &relation["m.in_reply_to"]);
+            text = match in_reply_to {
+                Some(in_reply_to) if in_reply_to["event_id"].is_string() => {
+                    replies::strip_fallback(&self.body)
+                }
+                _ => self.body.clone(),
+            };
+        }
+        match self.filename {
+            // files can be found by their name and caption alike
+            Some(filename) if filename != self.body => format!("{}\n{}", text, filename),
+            _ => text,
+        }
+    }
 }
 
+const HTML_FORMAT: &str = "org.matrix.custom.html";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EventName {
     pub event_id: String,
@@ -171,55 +374,631 @@ pub struct EventTopicContent {
     pub topic: String,
 }
 
-pub fn handle_message(radical: &mut Radical, message_in: Value) -> Result<Value> {
+/// Languages `initEventIndex` and `setIndexLanguage` accept, anything else
+/// falls back to a tokenizer without stemming.
+pub const LANGUAGES: &[&str] = &[
+    "arabic",
+    "danish",
+    "dutch",
+    "english",
+    "finnish",
+    "french",
+    "german",
+    "greek",
+    "hungarian",
+    "italian",
+    "japanese",
+    "portuguese",
+    "romanian",
+    "russian",
+    "spanish",
+    "swedish",
+    "tamil",
+    "turkish",
+];
+
+pub const NOTIFICATIONS: &[&str] = &["reindexProgress", "commitCompleted", "storeHealth"];
+
+/// The open event stores, handling `seshat` messages.
+///
+/// Clones share the same stores, which lets reindex threads add the store
+/// they're done with.
+#[derive(Clone, Default)]
+pub struct Seshat {
+    indexers: Arc<Mutex<IndexerMap>>,
+    reindexing: Arc<Mutex<HashMap<String, Reindex>>>,
+    notifier: Notifier,
+}
+
+impl Seshat {
+    pub fn new(notifier: Notifier) -> Seshat {
+        Seshat {
+            notifier,
+            ..Seshat::default()
+        }
+    }
+
+    /// `{eventStore, status}` with status one of `opened`, `closed`, `deleted`
+    /// or `unhealthy`, the latter with the `error` that made it so.
+    fn store_health(&self, event_store: &str, status: &str, error: Option<String>) {
+        self.notifier.notify(
+            "storeHealth",
+            json!({
+                "eventStore": event_store,
+                "status": status,
+                "error": error,
+            }),
+        );
+    }
+
+    fn reindex_progress(&self, event_store: &str, reindexed: u64, total: u64) {
+        self.notifier.notify(
+            "reindexProgress",
+            json!({
+                "eventStore": event_store,
+                "reindexed": reindexed,
+                "total": total,
+            }),
+        );
+    }
+}
+
+/// A reindex running in the background. Requests for its event store get a
+/// `reindexInProgress` error until it's done and the store is opened.
+struct Reindex {
+    stop: CancelToken,
+    progress: Arc<ReindexProgress>,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct ReindexProgress {
+    reindexed: AtomicU64,
+    total: AtomicU64,
+}
+
+impl ReindexProgress {
+    fn error(&self) -> Error {
+        Error::ReindexInProgress {
+            reindexed: self.reindexed.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+        }
+    }
+
+    fn status(&self) -> Value {
+        json!({
+            "status": "reindexing",
+            "reindexed": self.reindexed.load(Ordering::Relaxed),
+            "total": self.total.load(Ordering::Relaxed),
+        })
+    }
+}
+
+impl Reindex {
+    fn start(seshat: &Seshat, event_store: String, config: Config) -> Result<Value> {
+        let path = Indexer::event_store_path(&event_store)?;
+        let stop = CancelToken::default();
+        let progress = Arc::new(ReindexProgress::default());
+
+        // holding the lock until the reindex is added keeps the thread from
+        // removing it before that
+        let mut reindexing = reindexing(seshat);
+        let handle = {
+            let seshat = seshat.clone();
+            let event_store = event_store.clone();
+            let stop = stop.clone();
+            let progress = progress.clone();
+            thread::spawn(move || {
+                Reindex::run(&seshat, &event_store, path, config, &stop, &progress)
+            })
+        };
+        let status = progress.status();
+        reindexing.insert(
+            event_store,
+            Reindex {
+                stop,
+                progress,
+                handle,
+            },
+        );
+
+        Ok(status)
+    }
+
+    fn run(
+        seshat: &Seshat,
+        event_store: &str,
+        path: PathBuf,
+        config: Config,
+        stop: &CancelToken,
+        progress: &ReindexProgress,
+    ) {
+        let mut report = |reindexed, total| {
+            progress.reindexed.store(reindexed, Ordering::Relaxed);
+            progress.total.store(total, Ordering::Relaxed);
+            seshat.reindex_progress(event_store, reindexed, total);
+        };
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            Indexer::reindex(&path, &config, &mut report, stop)?;
+            Indexer::new_in_path(path, config)
+        }))
+        .unwrap_or_else(|_| Err(Error::Internal("reindex panicked".to_owned())));
+
+        let health = match res {
+            Ok(indexer) => {
+                // added before the reindex is removed, so requests always see
+                // one of them
+                indexers(seshat).insert(event_store.to_owned(), Arc::new(Mutex::new(indexer)));
+                Some(("opened", None))
+            }
+            Err(Error::Cancelled) => None,
+            Err(error) => {
+                eprintln!("reindexing {} failed: {}", event_store, error);
+                Some(("unhealthy", Some(error.to_string())))
+            }
+        };
+        reindexing(seshat).remove(event_store);
+        // only once requests don't get `reindexInProgress` anymore
+        if let Some((status, error)) = health {
+            seshat.store_health(event_store, status, error);
+        }
+    }
+
+    /// Stops a running reindex and waits for its thread. The reindex starts
+    /// over the next time the event store is opened.
+    fn stop(event_store: &str, seshat: &Seshat) {
+        let reindex = reindexing(seshat).remove(event_store);
+        if let Some(reindex) = reindex {
+            reindex.stop.cancel();
+            if reindex.handle.join().is_err() {
+                eprintln!("reindex thread for {} panicked", event_store);
+            }
+        }
+    }
+}
+
+impl Handler for Seshat {
+    fn methods(&self) -> &'static [&'static str] {
+        METHODS
+    }
+
+    fn capabilities(&self) -> Option<Value> {
+        Some(json!({ "languages": LANGUAGES }))
+    }
+
+    fn notifications(&self) -> &'static [&'static str] {
+        NOTIFICATIONS
+    }
+
+    // one lane per event store
+    fn lane(&self, message: &Value) -> Result<Option<String>> {
+        // searches across event stores don't belong to any of them
+        if message.get("method") == Some(&json!("searchEventIndexes")) {
+            return Ok(Some("*".to_owned()));
+        }
+        event_store(message).map(Some)
+    }
+
+    fn handle(&self, message: Value, cancel: &CancelToken) -> Result<Value> {
+        handle_message(self, message, cancel)
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        shutdown_all(self)
+    }
+}
+
+pub fn event_store(message_in: &Value) -> Result<String> {
     let event_store = match message_in.get("eventStore") {
-        Some(res) => res.as_str().context("eventStore.as_str() failed")?,
+        Some(res) => res
+            .as_str()
+            .ok_or_else(|| Error::InvalidMessage("eventStore is not a string".to_owned()))?,
         None => "default",
-    }
-    .to_owned();
+    };
+    Ok(event_store.to_owned())
+}
+
+pub fn handle_message(seshat: &Seshat, message_in: Value, cancel: &CancelToken) -> Result<Value> {
+    let event_store = event_store(&message_in)?;
     let message: Message = serde_json::from_value(message_in)?;
+    if let Message::SearchEventIndexes { content } = message {
+        return search_event_indexes(seshat, content, cancel);
+    }
+
+    let reindex = reindexing(seshat)
+        .get(&event_store)
+        .map(|reindex| reindex.progress.clone());
+    if let Some(progress) = reindex {
+        match message {
+            Message::InitEventIndex(_) => return Ok(progress.status()),
+            Message::CloseEventIndex | Message::DeleteEventIndex => {
+                Reindex::stop(&event_store, seshat)
+            }
+            _ => return Err(progress.error()),
+        }
+    }
 
-    let res = match radical.indexer.get_mut(&event_store) {
+    let indexer = indexers(seshat).get(&event_store).cloned();
+    let indexer = match indexer {
+        // a request panicked while holding the lock, so the indexer might be
+        // in any state and gets replaced before it's used again
+        Some(indexer) if indexer.is_poisoned() => {
+            drop(indexer);
+            let error = "a request panicked".to_owned();
+            seshat.store_health(&event_store, "unhealthy", Some(error));
+            let indexer = Indexer::reopen(&event_store, seshat)?;
+            seshat.store_health(&event_store, "opened", None);
+            Some(indexer)
+        }
+        indexer => indexer,
+    };
+    let res = match indexer {
         None => match message {
             Message::InitEventIndex(message) => {
-                let config = Indexer::config(message);
-                let indexer = Indexer::new(&event_store, config)?;
-                radical.indexer.insert(event_store.to_owned(), indexer);
-                json!(null)
+                let path = Indexer::event_store_path(&event_store)?;
+                if let Some(formatted_body) = message.formatted_body {
+                    Indexer::set_formatted_body(&path, formatted_body)?;
+                }
+                let settings = StoreSettings::load(&path)?;
+                let config = Indexer::config(message, settings);
+                match Indexer::new(&event_store, config.clone()) {
+                    Ok(indexer) => {
+                        let indexer = Arc::new(Mutex::new(indexer));
+                        indexers(seshat).insert(event_store.clone(), indexer);
+                        seshat.store_health(&event_store, "opened", None);
+                        json!(null)
+                    }
+                    Err(Error::Seshat(SeshatError::ReindexError)) => {
+                        Reindex::start(seshat, event_store, config)?
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            Message::DeleteEventIndex => {
+                let res = Indexer::delete(&event_store, seshat)?;
+                seshat.store_health(&event_store, "deleted", None);
+                res
             }
-            Message::DeleteEventIndex => Indexer::delete(&event_store, &mut radical.indexer)?,
             Message::CloseEventIndex => json!(null), // no-op
-            _ => bail!("index not initialized"),
+            _ => return Err(Error::IndexNotInitialized),
         },
         Some(indexer) => match message {
-            Message::LoadCheckpoints => indexer.load_checkpoints()?,
-            Message::IsEventIndexEmpty => indexer.is_event_index_empty()?,
-            Message::IsRoomIndexed { content } => indexer.is_room_indexed(content)?,
-            Message::CommitLiveEvents => indexer.commit_live_events()?,
-            Message::AddEventToIndex { content } => indexer.add_event_to_index(content)?,
-            Message::AddCrawlerCheckpoint { content } => indexer.add_history_events(content)?,
-            Message::AddHistoricEvents { content } => indexer.add_history_events(content)?,
-            Message::RemoveCrawlerCheckpoint { content } => indexer.add_history_events(content)?,
-            Message::SearchEventIndex { content } => indexer.search_event_index(content)?,
-            Message::LoadFileEvents { content } => indexer.load_file_events(content)?,
-            Message::GetUserVersion => indexer.get_user_version()?,
-            Message::SetUserVersion { content } => indexer.set_user_version(content)?,
-            Message::DeleteEvent { content } => indexer.delete_event(content)?,
-            Message::GetStats => indexer.get_stats()?,
-            Message::CloseEventIndex => Indexer::shutdown(&event_store, &mut radical.indexer)?,
-            Message::DeleteEventIndex => Indexer::delete(&event_store, &mut radical.indexer)?,
+            Message::CloseEventIndex => {
+                drop(indexer);
+                let res = Indexer::shutdown(&event_store, seshat)?;
+                seshat.store_health(&event_store, "closed", None);
+                res
+            }
+            Message::DeleteEventIndex => {
+                drop(indexer);
+                let res = Indexer::delete(&event_store, seshat)?;
+                seshat.store_health(&event_store, "deleted", None);
+                res
+            }
             Message::InitEventIndex(_) => json!(null), // no-op
+            Message::ReindexEventIndex { content } => {
+                drop(indexer);
+                Indexer::rebuild(&event_store, seshat, content.language)?
+            }
+            Message::SetIndexLanguage { content } => {
+                drop(indexer);
+                Indexer::rebuild(&event_store, seshat, Some(content.language))?
+            }
+            message => {
+                let committed = matches!(message, Message::CommitLiveEvents);
+                let mut indexer = indexer
+                    .lock()
+                    .map_err(|_| Error::Internal("index lock poisoned".to_owned()))?;
+                let res = indexer.handle(message, cancel)?;
+                if committed {
+                    let params = json!({ "eventStore": event_store });
+                    seshat.notifier.notify("commitCompleted", params);
+                }
+                res
+            }
         },
     };
 
     Ok(res)
 }
 
-pub type IndexerMap = HashMap<String, Indexer>;
+/// Searches several event stores and merges their results by rank, the most
+/// recent first among equally ranked ones.
+///
+/// Every event store is asked for as many results as a page holds, so the
+/// page can be filled from any of them. The results that didn't make it onto
+/// the page are asked for again on the next one.
+fn search_event_indexes(
+    seshat: &Seshat,
+    message: SearchEventIndexes,
+    cancel: &CancelToken,
+) -> Result<Value> {
+    let previous: SearchBatch = match &message.next_batch {
+        Some(next_batch) => base64::decode(next_batch)
+            .ok()
+            .and_then(|batch| serde_json::from_slice(&batch).ok())
+            .ok_or_else(|| Error::InvalidMessage("invalid next_batch".to_owned()))?,
+        None => SearchBatch {
+            cursors: match message.event_stores {
+                Some(event_stores) => event_stores,
+                None => indexers(seshat).keys().cloned().collect(),
+            }
+            .into_iter()
+            .map(|event_store| (event_store, SearchCursor::default()))
+            .collect(),
+            done: 0,
+        },
+    };
+    let limit = search_limit(&message.config)?;
+
+    let mut count = previous.done;
+    let mut highlights = BTreeSet::new();
+    let mut batches = Vec::new();
+    // results by the index of the batch they're from
+    let mut merged: Vec<(usize, SearchResult)> = Vec::new();
+    for (event_store, cursor) in previous.cursors {
+        let indexer = indexers(seshat).get(&event_store).cloned();
+        let indexer = indexer.ok_or(Error::IndexNotInitialized)?;
+        let indexer = indexer
+            .lock()
+            .map_err(|_| Error::Internal("index lock poisoned".to_owned()))?;
+
+        let mut config = message.config.clone();
+        config.limit(cursor.skip + limit);
+        let batch = indexer.search(
+            SearchEventIndex {
+                term: message.term.clone(),
+                config,
+                snippet_width: message.snippet_width,
+                filter: message.filter.clone(),
+                next_batch: cursor.token.clone(),
+            },
+            cancel,
+        )?;
+        count += batch.count;
+        highlights.extend(batch.highlights);
+        for mut result in batch.results.into_iter().skip(cursor.skip) {
+            result.event_store = Some(event_store.clone());
+            merged.push((batches.len(), result));
+        }
+        batches.push((
+            event_store,
+            cursor,
+            batch.count,
+            batch.next_batch,
+            batch.batch,
+        ));
+    }
+
+    merged.sort_by(|(_, a), (_, b)| {
+        let ts = |result: &SearchResult| result.result["origin_server_ts"].as_i64();
+        b.rank
+            .partial_cmp(&a.rank)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| ts(b).cmp(&ts(a)))
+    });
+    let rest = merged.split_off(limit.min(merged.len()));
+
+    let mut next = SearchBatch {
+        done: previous.done,
+        ..SearchBatch::default()
+    };
+    for (index, (event_store, cursor, count, next_batch, this_batch)) in
+        batches.into_iter().enumerate()
+    {
+        let returned = merged.iter().filter(|(from, _)| *from == index).count();
+        let left = rest.iter().any(|(from, _)| *from == index);
+        let cursor = if left {
+            // e.g. keeps the count of filtered searches, which would
+            // otherwise be counted again
+            SearchCursor {
+                token: this_batch,
+                skip: cursor.skip + returned,
+            }
+        } else {
+            match next_batch {
+                Some(token) => SearchCursor {
+                    token: Some(token),
+                    skip: 0,
+                },
+                // nothing left in this event store
+                None => {
+                    next.done += count;
+                    continue;
+                }
+            }
+        };
+        next.cursors.insert(event_store, cursor);
+    }
+    let next_batch = if next.cursors.is_empty() {
+        None
+    } else {
+        Some(base64::encode(serde_json::to_vec(&next)?))
+    };
+
+    Ok(json!(SearchResults {
+        count,
+        results: merged.into_iter().map(|(_, result)| result).collect(),
+        highlights: highlights.into_iter().collect(),
+        next_batch,
+        batch: None,
+    }))
+}
+
+// SearchConfig keeps its fields to itself
+fn search_limit(config: &SearchConfig) -> Result<usize> {
+    let limit = serde_json::to_value(config)?["limit"].as_u64();
+    Ok(limit.unwrap_or_default() as usize)
+}
+
+fn take_room_id(config: &mut SearchConfig) -> Result<Option<String>> {
+    let mut value = serde_json::to_value(&*config)?;
+    let room_id = value["room_id"].take();
+    *config = serde_json::from_value(value)?;
+    Ok(room_id.as_str().map(str::to_owned))
+}
+
+/// Commits pending live events and shuts down every open event store, trying
+/// all of them even if some fail.
+pub fn shutdown_all(seshat: &Seshat) -> Result<()> {
+    let event_stores: Vec<String> = reindexing(seshat).keys().cloned().collect();
+    for event_store in event_stores {
+        Reindex::stop(&event_store, seshat);
+    }
+
+    let event_stores: Vec<String> = indexers(seshat).keys().cloned().collect();
+
+    let mut failed = Vec::new();
+    for event_store in event_stores {
+        if let Err(error) = Indexer::commit_and_shutdown(&event_store, seshat) {
+            eprintln!("shutting down {} failed: {}", event_store, error);
+            failed.push(event_store);
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(Error::Internal(format!(
+            "shutting down event stores failed: {}",
+            failed.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+fn indexers(seshat: &Seshat) -> MutexGuard<'_, IndexerMap> {
+    lock(&seshat.indexers)
+}
+
+fn reindexing(seshat: &Seshat) -> MutexGuard<'_, HashMap<String, Reindex>> {
+    lock(&seshat.reindexing)
+}
+
+/// Where the host keeps its settings for an event store, a directory seshat
+/// leaves alone when it deletes the index.
+const SETTINGS_DIR: &str = "settings";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct StoreSettings {
+    language: Option<String>,
+    #[serde(default)]
+    formatted_body: bool,
+}
+
+impl StoreSettings {
+    fn file(path: &Path) -> PathBuf {
+        path.join(SETTINGS_DIR).join("settings.json")
+    }
+
+    fn load(path: &Path) -> Result<StoreSettings> {
+        let file = StoreSettings::file(path);
+        if !file.exists() {
+            return Ok(StoreSettings::default());
+        }
+        let settings = std::fs::read(file)?;
+        Ok(serde_json::from_slice(&settings)?)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path.join(SETTINGS_DIR))?;
+        write_atomically(&StoreSettings::file(path), &serde_json::to_vec(self)?)
+    }
+}
+
+/// Writes next to the file and renames, so a crash never leaves half a file.
+fn write_atomically(file: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = file.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, file)?;
+    Ok(())
+}
+
+/// Where reindex checkpoints are kept, inside the event store.
+const REINDEX_DIR: &str = "reindex";
+
+/// Marks a rebuild asked for with `reindexEventIndex`, `setIndexLanguage` or
+/// a different `formattedBody`, until it's done. Seshat only knows about the reindexes it needs itself.
+fn rebuild_marker(path: &Path) -> PathBuf {
+    path.join(REINDEX_DIR).join("rebuild")
+}
+
+/// The last event a reindex committed, events are reindexed from newest to
+/// oldest.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReindexCheckpoint {
+    event_id: String,
+    server_ts: i64,
+    reindexed: u64,
+}
+
+impl ReindexCheckpoint {
+    fn new(event: &seshat::Event, reindexed: u64) -> ReindexCheckpoint {
+        ReindexCheckpoint {
+            event_id: event.event_id.clone(),
+            server_ts: event.server_ts,
+            reindexed,
+        }
+    }
+
+    fn file(path: &Path) -> PathBuf {
+        path.join(REINDEX_DIR).join("checkpoint.json")
+    }
+
+    /// Exists while a commit is under way. Seshat can't tell whether one
+    /// that got interrupted made it into the index, so the checkpoint before
+    /// it isn't trusted then.
+    fn committing(path: &Path) -> PathBuf {
+        path.join(REINDEX_DIR).join("committing")
+    }
+
+    fn load(path: &Path) -> Result<Option<ReindexCheckpoint>> {
+        let file = ReindexCheckpoint::file(path);
+        if !file.exists() || ReindexCheckpoint::committing(path).exists() {
+            return Ok(None);
+        }
+        let checkpoint = std::fs::read(file)?;
+        Ok(Some(serde_json::from_slice(&checkpoint)?))
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let file = ReindexCheckpoint::file(path);
+        std::fs::create_dir_all(path.join(REINDEX_DIR))?;
+        write_atomically(&file, &serde_json::to_vec(self)?)
+    }
+
+    fn remove(path: &Path) -> Result<()> {
+        let dir = path.join(REINDEX_DIR);
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Seshat continues loading events after this one, it only looks at the
+    /// event id and timestamp.
+    fn into_event(self) -> seshat::Event {
+        seshat::Event {
+            event_type: EventType::Message,
+            content_value: String::new(),
+            msgtype: None,
+            event_id: self.event_id,
+            sender: String::new(),
+            server_ts: self.server_ts,
+            room_id: String::new(),
+            source: String::new(),
+        }
+    }
+}
+
+pub type IndexerMap = HashMap<String, Arc<Mutex<Indexer>>>;
 
 pub struct Indexer {
     database: Database,
     connection: Connection,
+    path: PathBuf,
+    config: Config,
+    formatted_body: bool,
 }
 
 impl Indexer {
@@ -227,68 +1006,212 @@ impl Indexer {
         let path = Indexer::event_store_path(event_store)?;
         std::fs::create_dir_all(&path)?;
 
-        Ok(Indexer::new_in_path(path, config)?)
+        Indexer::new_in_path(path, config)
     }
 
+    /// Opens the database, failing with `ReindexError` if it has to be
+    /// reindexed first.
     pub fn new_in_path(path: PathBuf, config: Config) -> Result<Indexer> {
-        let database = match Database::new_with_config(&path, &config) {
-            Ok(database) => database,
-            Err(error) => match error {
-                SeshatError::ReindexError => {
-                    Indexer::reindex(&path, &config)?;
-                    Database::new_with_config(path, &config)?
-                }
-                _ => bail!("Error opening the database: {:?}", error),
-            },
-        };
+        if rebuild_marker(&path).exists() {
+            return Err(SeshatError::ReindexError.into());
+        }
+        let database = Database::new_with_config(&path, &config)?;
+        // left over if the host died right after the reindex was done
+        ReindexCheckpoint::remove(&path)?;
 
         let connection = database.get_connection()?;
+        edits::create_table(&connection)?;
+        redactions::create_table(&connection)?;
+        let formatted_body = StoreSettings::load(&path)?.formatted_body;
         Ok(Indexer {
             database,
             connection,
+            path,
+            config,
+            formatted_body,
         })
     }
 
-    pub fn reindex(path: &PathBuf, config: &Config) -> Result<()> {
-        let mut db = RecoveryDatabase::new_with_config(&path, config)?;
+    /// Stores which body gets indexed, marking an existing index for a
+    /// rebuild when that changes.
+    fn set_formatted_body(path: &Path, formatted_body: bool) -> Result<()> {
+        let mut settings = StoreSettings::load(path)?;
+        if formatted_body == settings.formatted_body {
+            return Ok(());
+        }
+        if path.join("events.db").exists() {
+            std::fs::create_dir_all(path.join(REINDEX_DIR))?;
+            std::fs::write(rebuild_marker(path), b"")?;
+        }
+        settings.formatted_body = formatted_body;
+        settings.save(path)
+    }
+
+    /// Rebuilds the search index from the stored events. `progress` gets the
+    /// number of reindexed and total events after every batch, `stop` is
+    /// checked in between.
+    ///
+    /// Progress is checkpointed on disk with every commit, a reindex that got
+    /// interrupted continues from the last checkpoint.
+    pub fn reindex(
+        path: &PathBuf,
+        config: &Config,
+        progress: &mut dyn FnMut(u64, u64),
+        stop: &CancelToken,
+    ) -> Result<()> {
+        let mut db = RecoveryDatabase::new_with_config(path, config)?;
         // https://github.com/stoically/radical-native/issues/19#issuecomment-648382654
-        if db.get_connection()?.get_user_version()? == 0 {
+        // only for reindexes seshat needs, a rebuild keeps the events
+        let rebuild = rebuild_marker(path).exists();
+        if !rebuild && db.get_connection()?.get_user_version()? == 0 {
             db.shutdown()?;
-            if path.exists() {
-                std::fs::remove_dir_all(path)?;
+            // starts over, but with the same settings
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?.path();
+                if entry.ends_with(SETTINGS_DIR) {
+                    continue;
+                }
+                if entry.is_dir() {
+                    std::fs::remove_dir_all(entry)?;
+                } else {
+                    std::fs::remove_file(entry)?;
+                }
             }
         } else {
             // copy&paste'd from
             // https://github.com/matrix-org/seshat/blob/96d02b6e5d3a53db0174361aee36a02936c40bfa/seshat-node/native/src/tasks.rs#L387
             // this will probably move upstream into dedicated methods at some point,
             // when design decisions about reindex progress in the riot UI are made
-            db.delete_the_index()?;
+            let formatted_body = StoreSettings::load(path)?.formatted_body;
+            let checkpoint = ReindexCheckpoint::load(path)?;
+            if checkpoint.is_some() {
+                Indexer::keep_partial_index(path, &mut db)?;
+            } else {
+                // along with what was set aside for a checkpoint that isn't
+                // trusted
+                let aside = path.join(REINDEX_DIR).join("index");
+                if aside.exists() {
+                    std::fs::remove_dir_all(aside)?;
+                }
+                db.delete_the_index()?;
+            }
             db.open_index()?;
 
-            let mut events = db.load_events_deserialized(500, None)?;
-            db.index_events(&events)?;
+            let total = db.info().total_events();
+            let mut reindexed = checkpoint.as_ref().map_or(0, |c| c.reindexed);
+            let mut last = checkpoint.map(ReindexCheckpoint::into_event);
+            progress(reindexed, total);
 
             loop {
-                events = db.load_events_deserialized(500, events.last())?;
-
-                if events.is_empty() {
+                stop.check()?;
+                let sources = db.load_events(500, last.as_ref())?;
+                if sources.is_empty() {
                     break;
                 }
 
+                // converted like new events, seshat would only index the body
+                let mut events = Vec::new();
+                let mut next = None;
+                for source in &sources {
+                    let event: Value = serde_json::from_str(source).unwrap_or_default();
+                    match Indexer::convert_event(event.clone(), formatted_body) {
+                        Ok(event) => {
+                            next = Some(event.clone());
+                            events.push(event);
+                        }
+                        // the store might keep events from before a change in
+                        // what's indexed, they don't get in the way of the rest
+                        Err(error) => {
+                            let event_id = event["event_id"].as_str().unwrap_or_default();
+                            eprintln!("skipping event {} when reindexing: {}", event_id, error);
+                            next = Indexer::skipped_event(&event).or(next);
+                        }
+                    }
+                }
+
                 db.index_events(&events)?;
-                db.commit()?;
+                reindexed += sources.len() as u64;
+                last = match next {
+                    Some(next) => Some(next),
+                    None => {
+                        let error = "no event to continue reindexing after".to_owned();
+                        return Err(Error::Internal(error));
+                    }
+                };
+                // only what's committed survives a crash, resuming has to
+                // start right after it or events end up in the index twice
+                let committing = ReindexCheckpoint::committing(path);
+                std::fs::create_dir_all(path.join(REINDEX_DIR))?;
+                std::fs::write(&committing, b"")?;
+                if db.commit()? {
+                    if let Some(event) = &last {
+                        ReindexCheckpoint::new(event, reindexed).save(path)?;
+                    }
+                }
+                std::fs::remove_file(committing)?;
+                progress(reindexed, total);
             }
 
             db.commit_and_close()?;
+            ReindexCheckpoint::remove(path)?;
         }
 
         Ok(())
     }
 
+    /// Where loading events continues after one that doesn't convert, seshat
+    /// only looks at the event ID and timestamp.
+    fn skipped_event(event: &Value) -> Option<seshat::Event> {
+        Some(seshat::Event {
+            event_type: EventType::Message,
+            content_value: String::new(),
+            msgtype: None,
+            event_id: event["event_id"].as_str()?.to_owned(),
+            sender: String::new(),
+            server_ts: event["origin_server_ts"].as_i64()?,
+            room_id: String::new(),
+            source: String::new(),
+        })
+    }
+
+    /// `delete_the_index` is the only way to get seshat to open an index for
+    /// reindexing, so the partially rebuilt index is moved out of its way and
+    /// back. It only deletes files, which leaves our directory alone.
+    fn keep_partial_index(path: &Path, db: &mut RecoveryDatabase) -> Result<()> {
+        let aside = path.join(REINDEX_DIR).join("index");
+        // the host might have died while the index was moved aside
+        Indexer::move_files(&aside, path)?;
+        Indexer::move_files(path, &aside)?;
+        db.delete_the_index()?;
+        Indexer::move_files(&aside, path)?;
+        Ok(())
+    }
+
+    /// Moves every file but the events database from one directory to the
+    /// other.
+    fn move_files(from: &Path, to: &Path) -> Result<()> {
+        if !from.exists() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let path = entry?.path();
+            let file_name = match path.file_name() {
+                Some(file_name) if path.is_file() => file_name,
+                _ => continue,
+            };
+            if file_name.to_string_lossy().starts_with("events.db") {
+                continue;
+            }
+            std::fs::rename(&path, to.join(file_name))?;
+        }
+        Ok(())
+    }
+
     fn event_store_path(event_store: &str) -> Result<PathBuf> {
         let mut path = match dirs::data_local_dir() {
             Some(path) => path,
-            None => bail!("userdata dir not found"),
+            None => return Err(Error::Internal("userdata dir not found".to_owned())),
         };
         path.push("radical-native");
         path.push("EventStore");
@@ -296,15 +1219,109 @@ impl Indexer {
         Ok(path)
     }
 
-    fn shutdown(event_store: &str, indexer: &mut IndexerMap) -> Result<Value> {
-        if let Some(indexer) = indexer.remove(event_store) {
+    fn handle(&mut self, message: Message, cancel: &CancelToken) -> Result<Value> {
+        let res = match message {
+            Message::LoadCheckpoints => self.load_checkpoints()?,
+            Message::IsEventIndexEmpty => self.is_event_index_empty()?,
+            Message::IsRoomIndexed { content } => self.is_room_indexed(content)?,
+            Message::CommitLiveEvents => self.commit_live_events()?,
+            Message::AddEventToIndex { content } => self.add_event_to_index(content)?,
+            Message::AddCrawlerCheckpoint { content } => self.add_history_events(content)?,
+            Message::AddHistoricEvents { content } => self.add_history_events(content)?,
+            Message::RemoveCrawlerCheckpoint { content } => self.add_history_events(content)?,
+            Message::SearchEventIndex { content } => self.search_event_index(content, cancel)?,
+            Message::LoadFileEvents { content } => self.load_file_events(content)?,
+            Message::GetUserVersion => self.get_user_version()?,
+            Message::SetUserVersion { content } => self.set_user_version(content)?,
+            Message::DeleteEvent { content } => self.delete_event(content)?,
+            Message::GetStats => self.get_stats()?,
+            Message::InitEventIndex(_)
+            | Message::CloseEventIndex
+            | Message::DeleteEventIndex
+            | Message::ReindexEventIndex { .. }
+            | Message::SetIndexLanguage { .. }
+            | Message::SearchEventIndexes { .. } => unreachable!("handled by handle_message"),
+        };
+
+        Ok(res)
+    }
+
+    fn shutdown(event_store: &str, seshat: &Seshat) -> Result<Value> {
+        if let Some(indexer) = Indexer::take(event_store, seshat)? {
             indexer.database.shutdown().recv()??;
         }
         Ok(json!(null))
     }
 
-    fn delete(event_store: &str, indexer: &mut IndexerMap) -> Result<Value> {
-        Indexer::shutdown(event_store, indexer)?;
+    /// Removes the indexer from the map, handing out the only reference.
+    fn take(event_store: &str, seshat: &Seshat) -> Result<Option<Indexer>> {
+        let mut indexer = match indexers(seshat).remove(event_store) {
+            Some(indexer) => indexer,
+            None => return Ok(None),
+        };
+        // requests for the same event store run in order on one lane, but a
+        // search across event stores on its own lane might still be using the
+        // indexer, it can't get hold of it again once it's out of the map
+        loop {
+            match Arc::try_unwrap(indexer) {
+                Ok(indexer) => {
+                    return Ok(Some(
+                        indexer.into_inner().unwrap_or_else(PoisonError::into_inner),
+                    ))
+                }
+                Err(shared) => {
+                    drop(lock(&shared));
+                    indexer = shared;
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+
+    /// Commits pending live events and shuts the database down.
+    fn close(mut database: Database) -> Result<()> {
+        // seshat panics when its writer thread is gone, in which case there's
+        // nothing left to commit anyway
+        panic::catch_unwind(AssertUnwindSafe(move || -> Result<()> {
+            database.force_commit()?;
+            database.shutdown().recv()??;
+            Ok(())
+        }))
+        .unwrap_or_else(|_| Err(Error::Internal("seshat writer thread gone".to_owned())))
+    }
+
+    /// Closes an indexer that was poisoned by a panic and opens the event
+    /// store again, keeping whatever was indexed before the panic.
+    fn reopen(event_store: &str, seshat: &Seshat) -> Result<Arc<Mutex<Indexer>>> {
+        let indexer = Indexer::take(event_store, seshat)?.ok_or(Error::IndexNotInitialized)?;
+        let Indexer {
+            database,
+            path,
+            config,
+            ..
+        } = indexer;
+
+        match Indexer::close(database) {
+            Ok(()) => eprintln!("reopening {} after a panic", event_store),
+            Err(error) => eprintln!("closing {} after a panic failed: {}", event_store, error),
+        }
+
+        let indexer = Arc::new(Mutex::new(Indexer::new_in_path(path, config)?));
+        indexers(seshat).insert(event_store.to_owned(), indexer.clone());
+        Ok(indexer)
+    }
+
+    fn commit_and_shutdown(event_store: &str, seshat: &Seshat) -> Result<Value> {
+        if let Some(indexer) = Indexer::take(event_store, seshat)? {
+            // live events are only committed when riot asks for it, which it
+            // didn't get to if the browser went away
+            Indexer::close(indexer.database)?;
+        }
+        Ok(json!(null))
+    }
+
+    fn delete(event_store: &str, seshat: &Seshat) -> Result<Value> {
+        Indexer::shutdown(event_store, seshat)?;
 
         let path = Indexer::event_store_path(event_store)?;
         if path.exists() {
@@ -314,16 +1331,45 @@ impl Indexer {
         Ok(json!(null))
     }
 
-    fn config(message: InitEventIndex) -> Config {
-        let mut config = Config::new();
+    /// Closes the event store and rebuilds its index in the background, with
+    /// another language if one is given.
+    fn rebuild(event_store: &str, seshat: &Seshat, language: Option<String>) -> Result<Value> {
+        let indexer = Indexer::take(event_store, seshat)?.ok_or(Error::IndexNotInitialized)?;
+        let Indexer {
+            database,
+            path,
+            mut config,
+            ..
+        } = indexer;
+        Indexer::close(database)?;
+
+        // an interrupted rebuild continues the next time the event store is
+        // opened
+        std::fs::create_dir_all(path.join(REINDEX_DIR))?;
+        std::fs::write(rebuild_marker(&path), b"")?;
+        if let Some(language) = language {
+            config = config.set_language(&Language::from(language.as_str()));
+            let mut settings = StoreSettings::load(&path)?;
+            settings.language = Some(language);
+            settings.save(&path)?;
+        }
+        seshat.store_health(event_store, "closed", None);
 
-        let passphrase = match message.passphrase {
+        Reindex::start(seshat, event_store.to_owned(), config)
+    }
+
+    /// The language set with `setIndexLanguage` wins over the one given here,
+    /// an index can only be opened with the language it was built with.
+    fn config(message: InitEventIndex, settings: StoreSettings) -> Config {
+        let mut config = Config::new();
+
+        let passphrase = match message.passphrase {
             Some(passphrase) => passphrase,
             None => "DEFAULT_PASSPHRASE".to_owned(),
         };
         config = config.set_passphrase(passphrase);
 
-        if let Some(language) = message.language {
+        if let Some(language) = settings.language.or(message.language) {
             let language = Language::from(language.as_str());
             config = config.set_language(&language);
         }
@@ -331,14 +1377,14 @@ impl Indexer {
         config
     }
 
-    fn convert_event(event: Value) -> Result<seshat::Event> {
+    fn convert_event(event: Value, formatted_body: bool) -> Result<seshat::Event> {
         let source = serde_json::to_string(&event)?;
         let event: Event = serde_json::from_value(event)?;
         let res = match event {
             Event::Message(ev) => seshat::Event {
                 event_type: EventType::Message,
-                content_value: ev.content.body,
-                msgtype: ev.content.msgtype,
+                msgtype: ev.content.msgtype.clone(),
+                content_value: ev.content.text(formatted_body),
                 event_id: ev.event_id,
                 sender: ev.sender,
                 server_ts: ev.server_ts,
@@ -390,37 +1436,232 @@ impl Indexer {
         Ok(json!(true))
     }
 
-    fn add_event_to_index(&self, message: AddEventToIndex) -> Result<Value> {
-        let event = Indexer::convert_event(message.ev)?;
+    fn add_event_to_index(&mut self, message: AddEventToIndex) -> Result<Value> {
+        if let Some(target) = redactions::target(&message.ev) {
+            self.redact(&[target])?;
+            return Ok(json!(null));
+        }
+        if self.is_redacted(&message.ev)? {
+            return Ok(json!(null));
+        }
+
+        let event = match edits::target(&message.ev) {
+            Some(target) => {
+                // the edited event might still be waiting for a commit
+                self.database.force_commit()?;
+                match self.edit(target, &message.ev)? {
+                    Edited::Replaced(event) => event,
+                    Edited::Pending | Edited::Outdated => return Ok(json!(null)),
+                }
+            }
+            None => self.with_pending_edit(message.ev)?,
+        };
+        let event = Indexer::convert_event(event, self.formatted_body)?;
         self.database.add_event(event, message.profile);
         Ok(json!(null))
     }
 
-    fn add_history_events(&self, message: AddHistoricEvents) -> Result<Value> {
-        let mut events: Vec<(seshat::Event, Profile)> = Vec::new();
-        if let Some(events_in) = message.events {
-            for event_in in events_in {
-                let profile = event_in.profile;
-                let event = Indexer::convert_event(event_in.event)?;
-                events.push((event, profile));
+    fn add_history_events(&mut self, message: AddHistoricEvents) -> Result<Value> {
+        let events = message.events.unwrap_or_default();
+        // whether the batch had anything new besides the events seshat sees
+        let targets: Vec<&str> = events
+            .iter()
+            .filter_map(|event_in| redactions::target(&event_in.event))
+            .collect();
+        let redacted = self.redact(&targets)?;
+        let mut changed = redacted;
+
+        let mut events_in = Vec::new();
+        let mut edits_in = Vec::new();
+        for event_in in events {
+            if redactions::target(&event_in.event).is_some() || self.is_redacted(&event_in.event)? {
+                continue;
+            }
+            match edits::target(&event_in.event) {
+                Some(_) => edits_in.push(event_in),
+                None => events_in.push(Events {
+                    event: self.with_pending_edit(event_in.event)?,
+                    profile: event_in.profile,
+                }),
+            }
+        }
+
+        // edited events might still be waiting for a commit, unless the
+        // redactions committed them already
+        if !edits_in.is_empty() && !redacted {
+            self.database.force_commit()?;
+        }
+        // crawling goes back in time, so edits usually come before their
+        // event, which is often in the same batch
+        for edit in edits_in {
+            let target = edits::target(&edit.event).unwrap_or_default();
+            let in_batch = events_in
+                .iter()
+                .position(|event_in| event_in.event["event_id"] == target);
+            if let Some(i) = in_batch {
+                // seshat skips events it has already, edited or not
+                if edits::load_source(&self.connection, target)?.is_none() {
+                    changed |= edits::apply(&mut events_in[i].event, &edit.event);
+                    continue;
+                }
+            }
+            match self.edit(target, &edit.event)? {
+                Edited::Replaced(event) => {
+                    changed = true;
+                    let event_in = Events {
+                        event,
+                        profile: edit.profile,
+                    };
+                    match in_batch {
+                        Some(i) => events_in[i] = event_in,
+                        None => events_in.push(event_in),
+                    }
+                }
+                Edited::Pending => changed = true,
+                Edited::Outdated => (),
             }
         }
 
+        let mut events: Vec<(seshat::Event, Profile)> = Vec::new();
+        for event_in in events_in {
+            let profile = event_in.profile;
+            let event = Indexer::convert_event(event_in.event, self.formatted_body)?;
+            events.push((event, profile));
+        }
+
         let res = self
             .database
             .add_historic_events(events, message.checkpoint, message.old_checkpoint)
             .recv()??;
 
-        Ok(json!(res))
+        // a batch of edits or redactions that were new to us isn't the end
+        // of the crawl
+        Ok(json!(res && !changed))
     }
 
-    fn search_event_index(&self, message: SearchEventIndex) -> Result<Value> {
+    /// Applies an edit to the event in the store, which is deleted so that
+    /// it can be added again with the new content. Seshat only finds the
+    /// event once it's committed.
+    fn edit(&mut self, target: &str, edit: &Value) -> Result<Edited> {
+        if redactions::contains(&self.connection, target)? {
+            return Ok(Edited::Outdated);
+        }
+        let mut event = match edits::load_source(&self.connection, target)? {
+            Some(event) => event,
+            None => {
+                edits::save_pending(&self.connection, target, edit)?;
+                return Ok(Edited::Pending);
+            }
+        };
+        if !edits::apply(&mut event, edit) {
+            return Ok(Edited::Outdated);
+        }
+        self.database.delete_event(target).recv()??;
+        Ok(Edited::Replaced(event))
+    }
+
+    /// Deletes the redacted events and keeps them from being indexed later
+    /// on. Returns whether any of the redactions is new, in which case live
+    /// events were committed.
+    fn redact(&mut self, targets: &[&str]) -> Result<bool> {
+        let mut new = Vec::new();
+        for target in targets {
+            if redactions::remember(&self.connection, target)? {
+                edits::take_pending(&self.connection, target)?;
+                new.push(*target);
+            }
+        }
+        if new.is_empty() {
+            return Ok(false);
+        }
+        // live events only show up in the store and can be deleted once
+        // they're committed
+        self.database.force_commit()?;
+        for target in new {
+            if redactions::in_store(&self.connection, target)? {
+                self.database.delete_event(target).recv()??;
+            }
+        }
+        Ok(true)
+    }
+
+    fn is_redacted(&self, event: &Value) -> Result<bool> {
+        let event_id = event["event_id"].as_str().unwrap_or_default();
+        Ok(redactions::is_redacted(event) || redactions::contains(&self.connection, event_id)?)
+    }
+
+    fn with_pending_edit(&self, mut event: Value) -> Result<Value> {
+        let event_id = event["event_id"].as_str().unwrap_or_default().to_owned();
+        if let Some(edit) = edits::take_pending(&self.connection, &event_id)? {
+            edits::apply(&mut event, &edit);
+        }
+        Ok(event)
+    }
+
+    fn search_event_index(&self, message: SearchEventIndex, cancel: &CancelToken) -> Result<Value> {
+        Ok(json!(self.search(message, cancel)?))
+    }
+
+    fn search(&self, message: SearchEventIndex, cancel: &CancelToken) -> Result<SearchResults> {
+        let SearchEventIndex {
+            term,
+            mut config,
+            snippet_width,
+            filter,
+            next_batch,
+        } = message;
+        let query = Query::parse(&term)?;
+        let (term, filter) = if query.plain {
+            (term, filter)
+        } else {
+            // seshat would turn the whole term into a phrase to search a room
+            let room = take_room_id(&mut config)?.map(|room| SearchFilter {
+                rooms: Some(vec![room]),
+                ..SearchFilter::default()
+            });
+            let filters = vec![filter, query.filter, room].into_iter().flatten();
+            (query.text, filters.reduce(SearchFilter::and))
+        };
         let searcher = self.database.get_searcher();
-        let search_batch = searcher.search(&message.term, &message.config)?;
+        let (count, batch, next_batch, this_batch) = match filter {
+            Some(filter) => {
+                Indexer::search_filtered(&searcher, &term, config, &filter, next_batch, cancel)?
+            }
+            None => {
+                if let Some(token) = &next_batch {
+                    config.next_batch(serde_json::from_value(json!(token))?);
+                }
+                let batch = searcher.search(&term, &config)?;
+                let following = batch
+                    .next_batch
+                    .map(|next_batch| next_batch.to_hyphenated().to_string());
+                (batch.count, batch.results, following, next_batch)
+            }
+        };
+        let language = highlight::language(&self.config);
+        let mut highlighter = Highlighter::new(&language, &query.terms.join(" "));
 
+        // seshat can't be interrupted mid search, but we can skip decoding
+        // the results nobody is waiting for anymore
         let mut results = Vec::new();
-        for result in search_batch.results {
+        for result in batch {
+            cancel.check()?;
             let event: Value = serde_json::from_str(&result.event_source)?;
+            for field in &["body", "name", "topic"] {
+                if let Some(text) = event["content"][field].as_str() {
+                    highlighter.add_matches(text);
+                }
+            }
+            let snippets = snippet_width.map(|width| {
+                let content = &event["content"];
+                let body = content["body"].as_str().unwrap_or_default();
+                // the quote of a reply isn't searched, so matches in it aren't shown
+                if content["m.relates_to"]["m.in_reply_to"]["event_id"].is_string() {
+                    highlighter.snippets(&replies::strip_fallback(body), width)
+                } else {
+                    highlighter.snippets(body, width)
+                }
+            });
             let mut events_before = Vec::new();
             for event in result.events_before.iter() {
                 let event: Value = serde_json::from_str(event)?;
@@ -440,34 +1681,156 @@ impl Indexer {
                     events_after,
                     profile_info: result.profile_info,
                 },
+                snippets,
+                event_store: None,
             });
         }
 
-        let next_batch = match search_batch.next_batch {
-            Some(next_batch) => Some(next_batch.to_hyphenated().to_string()),
-            None => None,
-        };
-
-        let res = SearchResults {
-            count: search_batch.count,
+        Ok(SearchResults {
+            count,
             results,
-            highlights: vec![],
+            highlights: highlighter.highlights(),
             next_batch,
+            batch: this_batch,
+        })
+    }
+
+    /// Seshat can't filter by itself, so its batches are filtered until a page
+    /// is full. `next_batch` points into the batch the page ended in.
+    ///
+    /// Only the first page goes through all results, without context, to
+    /// count the matching ones, the `next_batch` of the following pages and
+    /// the one the page started from carry the count.
+    fn search_filtered(
+        searcher: &Searcher,
+        term: &str,
+        config: SearchConfig,
+        filter: &SearchFilter,
+        next_batch: Option<String>,
+        cancel: &CancelToken,
+    ) -> Result<FilteredPage> {
+        let start = match next_batch {
+            Some(next_batch) => base64::decode(next_batch)
+                .ok()
+                .and_then(|batch| serde_json::from_slice(&batch).ok())
+                .ok_or_else(|| Error::InvalidMessage("invalid next_batch".to_owned()))?,
+            None => FilteredBatch {
+                cursor: SearchCursor::default(),
+                count: Indexer::count_filtered(searcher, term, &config, filter, cancel)?,
+            },
         };
+        let this_batch = start.encode()?;
+        let FilteredBatch { mut cursor, count } = start;
+        let limit = search_limit(&config)?;
 
-        Ok(json!(res))
+        let mut results = Vec::new();
+        let next = loop {
+            if results.len() == limit {
+                break Some(cursor);
+            }
+            let mut batch = config.clone();
+            batch.limit(limit);
+            if let Some(token) = &cursor.token {
+                batch.next_batch(serde_json::from_value(json!(token))?);
+            }
+            let batch = searcher.search(term, &batch)?;
+            let mut full = None;
+            for (position, result) in batch.results.into_iter().enumerate().skip(cursor.skip) {
+                if results.len() == limit {
+                    full = Some(position);
+                    break;
+                }
+                cancel.check()?;
+                let event: Value = serde_json::from_str(&result.event_source)?;
+                if filter.matches(&event) {
+                    results.push(result);
+                }
+            }
+            if let Some(skip) = full {
+                cursor.skip = skip;
+                break Some(cursor);
+            }
+            match batch.next_batch {
+                Some(token) => {
+                    cursor = SearchCursor {
+                        token: Some(token.to_hyphenated().to_string()),
+                        skip: 0,
+                    }
+                }
+                None => break None,
+            }
+        };
+        let next_batch = match next {
+            Some(cursor) => Some(FilteredBatch { cursor, count }.encode()?),
+            None => None,
+        };
+
+        Ok((count, results, next_batch, Some(this_batch)))
+    }
+
+    fn count_filtered(
+        searcher: &Searcher,
+        term: &str,
+        config: &SearchConfig,
+        filter: &SearchFilter,
+        cancel: &CancelToken,
+    ) -> Result<usize> {
+        let mut all = config.clone();
+        all.limit(1).before_limit(0).after_limit(0);
+        let total = searcher.search(term, &all)?.count;
+        all.limit(total.max(1));
+        let mut count = 0;
+        for result in searcher.search(term, &all)?.results {
+            cancel.check()?;
+            let event: Value = serde_json::from_str(&result.event_source)?;
+            if filter.matches(&event) {
+                count += 1;
+            }
+        }
+        Ok(count)
     }
 
-    fn load_file_events(&self, message: LoadConfig) -> Result<Value> {
-        let ret = self.connection.load_file_events(&message)?;
+    /// Seshat can't filter file events by itself, so pages are loaded until
+    /// enough of them match or the room has no more.
+    fn load_file_events(&self, message: LoadFileEvents) -> Result<Value> {
+        // LoadConfig keeps its fields to itself
+        let mut config = serde_json::to_value(&message.config)?;
+        let limit = config["limit"].as_u64().unwrap_or_default() as usize;
+
         let mut results = Vec::new();
-        for (source, profile) in ret {
-            let event: Value = serde_json::from_str(&source)?;
-            results.push(FileEvent { event, profile });
+        // seshat hands out events sent at the same time as the one it
+        // continues from again
+        let mut seen = HashSet::new();
+        while results.len() < limit {
+            let page: LoadConfig = serde_json::from_value(config.clone())?;
+            let ret = self.connection.load_file_events(&page)?;
+            let mut done = ret.len() < limit;
+            let mut new = false;
+            for (source, profile) in ret {
+                let event: Value = serde_json::from_str(&source)?;
+                let event_id = event["event_id"].as_str().unwrap_or_default().to_owned();
+                if !seen.insert(event_id.clone()) {
+                    continue;
+                }
+                new = true;
+                config["fromEvent"] = json!(event_id);
+                if results.len() < limit && message.filter.matches(&event) {
+                    results.push(FileEvent { event, profile });
+                }
+            }
+            done |= !new;
+            if done {
+                break;
+            }
         }
         Ok(json!(results))
     }
 
+    /// Seshat sums up the sizes of the files in the event store, while
+    /// tantivy's reader creates and removes `.tantivy-meta.lock` as it reloads
+    /// in a thread of its own after every commit. Stats asked for right after
+    /// a commit can fail with a `seshatError` for a vanished file, and are
+    /// fine when asked for again.
     fn get_stats(&self) -> Result<Value> {
         let res = self.connection.get_stats()?;
         Ok(json!(res))
@@ -492,6 +1855,7 @@ impl Indexer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{sync::mpsc, time::Duration};
     use tempfile::tempdir;
 
     fn event_room_message_text() -> Value {
@@ -503,16 +1867,61 @@ mod tests {
                 "body": "Test message",
                 "msgtype": "m.text"
             },
-            "origin_server_ts": 1_580_728_702_628 as usize,
+            "origin_server_ts": 1_580_728_702_628_usize,
             "unsigned": {
-                "age": 949_499_816 as usize
+                "age": 949_499_816_usize
             },
             "event_id": "$lp49H7iDTNWQxD-fiZ6sDE6vT70DlYdKdoujEB5QtLM",
             "user_id": "@example2:localhost",
-            "age": 949_499_816 as usize
+            "age": 949_499_816_usize
         })
     }
 
+    /// The test message with `$ts` as event ID, sent at `ts`.
+    fn message_event(ts: i64) -> Value {
+        let mut event = event_room_message_text();
+        event["event_id"] = json!(format!("${}", ts));
+        event["origin_server_ts"] = json!(ts);
+        event
+    }
+
+    /// Adds the events live and makes them searchable.
+    fn index_events(indexer: &mut Indexer, events: Vec<Value>) {
+        for ev in events {
+            let payload = AddEventToIndex {
+                ev,
+                profile: Profile::new("Alice", ""),
+            };
+            indexer.add_event_to_index(payload).expect("add_event");
+        }
+        commit(indexer);
+    }
+
+    fn commit(indexer: &mut Indexer) {
+        indexer.database.force_commit().expect("force_commit");
+        indexer.database.reload().expect("reload");
+    }
+
+    /// Asks for stats again while tantivy reloads after a commit, see
+    /// `Indexer::get_stats`.
+    fn stats(get_stats: impl Fn() -> Result<Value>) -> Value {
+        for _ in 0..10 {
+            match get_stats() {
+                Err(Error::Seshat(SeshatError::FsError(_))) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                res => return res.expect("getStats"),
+            }
+        }
+        panic!("stats kept failing");
+    }
+
+    fn search(indexer: &Indexer, term: &str, filter: Value) -> Result<Value> {
+        let message = json!({ "term": term, "config": {}, "filter": filter });
+        let message = serde_json::from_value(message).expect("message");
+        indexer.search_event_index(message, &CancelToken::default())
+    }
+
     fn checkpoint() -> Value {
         json!({
             "roomId": "!FDVbSkWZSIcwvBFMdt:localhost",
@@ -538,7 +1947,7 @@ mod tests {
     #[test]
     fn crawler_checkpoints() {
         let tmpdir = tempdir().expect("tempdir");
-        let indexer = indexer(tmpdir.path());
+        let mut indexer = indexer(tmpdir.path());
         let checkpoint = checkpoint();
 
         let message: AddHistoricEvents = serde_json::from_value(json!({
@@ -563,7 +1972,7 @@ mod tests {
     #[test]
     fn initial_crawl() {
         let tmpdir = tempdir().expect("tempdir");
-        let indexer = indexer(tmpdir.path());
+        let mut indexer = indexer(tmpdir.path());
         let checkpoint = checkpoint();
         let profile = Profile::new("Alice", "");
 
@@ -618,31 +2027,73 @@ mod tests {
 
         indexer.commit_live_events().expect("commit_live_events");
 
-        let reply = indexer.get_stats().expect("get_stats");
+        let reply = stats(|| indexer.get_stats());
         assert_eq!(reply["eventCount"].as_i64().expect("eventCount"), 1);
     }
 
     #[test]
-    fn json_messages() {
-        let tmpdir = tempdir().expect("tempdir");
-        // make sure that we have only one test that modifies the environment
-        // since tests run in parallel
-        std::env::set_var("HOME", tmpdir.path().to_str().expect("tmpdir path"));
-        use std::collections::HashMap;
-        let mut radical = Radical {
-            indexer: HashMap::new(),
-        };
-        handle_message(
-            &mut radical,
+    fn uninitialized_index() {
+        let seshat = Seshat::default();
+        let cancel = CancelToken::default();
+        let error = handle_message(
+            &seshat,
             json!({
-                "method": "initEventIndex"
+                "method": "getStats",
+                "eventStore": "uninitialized"
             }),
+            &cancel,
         )
-        .expect("initEventIndex");
+        .unwrap_err();
+
+        assert_eq!(error.code(), "indexNotInitialized");
+    }
+
+    #[test]
+    fn cancelled_search() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
 
+        let profile = Profile::new("Alice", "");
+        let payload: AddEventToIndex = serde_json::from_value(json!({
+            "ev": event_room_message_text(),
+            "profile": profile
+        }))
+        .unwrap();
+        indexer
+            .add_event_to_index(payload)
+            .expect("add_event_to_index");
+        indexer.database.force_commit().expect("force_commit");
+        indexer.database.reload().expect("reload");
+
+        let search = || -> SearchEventIndex {
+            serde_json::from_value(json!({
+                "term": "message",
+                "config": {}
+            }))
+            .unwrap()
+        };
+        let cancel = CancelToken::default();
+        let reply = indexer
+            .search_event_index(search(), &cancel)
+            .expect("search_event_index");
+        assert_eq!(reply["count"], 1);
+
+        cancel.cancel();
+        let error = indexer.search_event_index(search(), &cancel).unwrap_err();
+        assert_eq!(error.code(), "cancelled");
+    }
+
+    #[test]
+    fn reopen_after_panic() {
+        let tmpdir = tempdir().expect("tempdir");
+        let seshat = Seshat::default();
+        let indexer = Arc::new(Mutex::new(indexer(tmpdir.path())));
+        indexers(&seshat).insert("default".to_owned(), indexer.clone());
+
+        let cancel = CancelToken::default();
         let profile = Profile::new("Alice", "");
         handle_message(
-            &mut radical,
+            &seshat,
             json!({
                 "method": "addEventToIndex",
                 "content": {
@@ -650,57 +2101,770 @@ mod tests {
                     "profile": profile
                 }
             }),
+            &cancel,
         )
         .expect("addEventToIndex");
 
-        handle_message(
-            &mut radical,
-            json!({
-                "method": "commitLiveEvents"
-            }),
-        )
-        .expect("commitLiveEvents");
+        let poison = indexer.clone();
+        std::thread::spawn(move || {
+            let _indexer = poison.lock().unwrap();
+            panic!("poison");
+        })
+        .join()
+        .unwrap_err();
+        assert!(indexer.is_poisoned());
+        drop(indexer);
+
+        // the uncommitted event survives reopening
+        let reply = stats(|| handle_message(&seshat, json!({ "method": "getStats" }), &cancel));
+        assert_eq!(reply["eventCount"], 1);
+        assert!(!indexers(&seshat)["default"].is_poisoned());
+    }
 
-        let checkpoint = checkpoint();
-        handle_message(
-            &mut radical,
-            json!({
-                "method": "addCrawlerCheckpoint",
+    #[test]
+    fn reindex_in_progress() {
+        let seshat = Seshat::default();
+        let stop = CancelToken::default();
+        let progress = Arc::new(ReindexProgress::default());
+        progress.reindexed.store(500, Ordering::Relaxed);
+        progress.total.store(1000, Ordering::Relaxed);
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.is_cancelled() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            })
+        };
+        reindexing(&seshat).insert(
+            "default".to_owned(),
+            Reindex {
+                stop: stop.clone(),
+                progress,
+                handle,
+            },
+        );
+
+        let cancel = CancelToken::default();
+        let reply = handle_message(&seshat, json!({ "method": "initEventIndex" }), &cancel)
+            .expect("initEventIndex");
+        assert_eq!(reply["status"], "reindexing");
+        assert_eq!(reply["reindexed"], 500);
+
+        let error = handle_message(&seshat, json!({ "method": "getStats" }), &cancel).unwrap_err();
+        assert_eq!(error.code(), "reindexInProgress");
+        assert_eq!(error.data(), json!({ "reindexed": 500, "total": 1000 }));
+
+        handle_message(&seshat, json!({ "method": "closeEventIndex" }), &cancel)
+            .expect("closeEventIndex");
+        assert!(stop.is_cancelled());
+        assert!(reindexing(&seshat).is_empty());
+    }
+
+    #[test]
+    fn resume_reindex() {
+        let tmpdir = tempdir().expect("tempdir");
+        let path = tmpdir.path().to_path_buf();
+        let config = Config::new().set_passphrase("TEST_PASS");
+        let mut database = Database::new_with_config(&path, &config).expect("database");
+        database
+            .get_connection()
+            .expect("connection")
+            .set_user_version(1)
+            .expect("set_user_version");
+        for i in 0..1200 {
+            let event_id = format!("$event{}", i);
+            let source = json!({
+                "type": "m.room.message",
+                "room_id": "!FDVbSkWZSIcwvBFMdt:localhost",
+                "sender": "@example2:localhost",
+                "content": { "body": "Test message", "msgtype": "m.text" },
+                "origin_server_ts": 1_580_728_702_628_i64 + i,
+                "event_id": event_id,
+            });
+            let event = seshat::Event::new(
+                EventType::Message,
+                "Test message",
+                Some("m.text"),
+                &event_id,
+                "@example2:localhost",
+                1_580_728_702_628 + i,
+                "!FDVbSkWZSIcwvBFMdt:localhost",
+                &source.to_string(),
+            );
+            database.add_event(event, Profile::new("Alice", ""));
+        }
+        database.force_commit().expect("force_commit");
+        database.shutdown().recv().unwrap().expect("shutdown");
+
+        // the host dies right after the first commit
+        let stop = CancelToken::default();
+        let mut progress = |reindexed, _| {
+            if reindexed >= 500 {
+                stop.cancel();
+            }
+        };
+        let error = Indexer::reindex(&path, &config, &mut progress, &stop).unwrap_err();
+        assert_eq!(error.code(), "cancelled");
+        let checkpoint = ReindexCheckpoint::load(&path)
+            .expect("load")
+            .expect("checkpoint");
+        assert_eq!(checkpoint.reindexed, 500);
+
+        // the host dies after the second commit, before its checkpoint is
+        // saved
+        let saved = std::fs::read(ReindexCheckpoint::file(&path)).expect("checkpoint");
+        let stop = CancelToken::default();
+        let mut progress = |reindexed, _| {
+            if reindexed >= 1000 {
+                stop.cancel();
+            }
+        };
+        Indexer::reindex(&path, &config, &mut progress, &stop).unwrap_err();
+        std::fs::write(ReindexCheckpoint::file(&path), saved).expect("checkpoint");
+        std::fs::write(ReindexCheckpoint::committing(&path), b"").expect("committing");
+        // whether the commit made it is unknown, so the reindex starts over
+        assert!(ReindexCheckpoint::load(&path).expect("load").is_none());
+        let stop = CancelToken::default();
+        let mut resumed_at = None;
+        let mut progress = |reindexed, _| {
+            resumed_at.get_or_insert(reindexed);
+            if reindexed >= 500 {
+                stop.cancel();
+            }
+        };
+        Indexer::reindex(&path, &config, &mut progress, &stop).unwrap_err();
+        assert_eq!(resumed_at, Some(0));
+
+        let mut resumed_at = None;
+        let mut progress = |reindexed, total| {
+            resumed_at.get_or_insert(reindexed);
+            assert_eq!(total, 1200);
+        };
+        Indexer::reindex(&path, &config, &mut progress, &CancelToken::default()).expect("reindex");
+        assert_eq!(resumed_at, Some(500));
+        assert!(ReindexCheckpoint::load(&path).expect("load").is_none());
+
+        // the events indexed before the crash are still there
+        let database = Database::new_with_config(&path, &config).expect("reindexed");
+        let results = database
+            .search("message", SearchConfig::new().limit(1))
+            .expect("search");
+        assert_eq!(results.count, 1200);
+    }
+
+    #[test]
+    fn interrupted_rebuild() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        index_events(&mut indexer, vec![event_room_message_text()]);
+        let Indexer {
+            database,
+            path,
+            config,
+            ..
+        } = indexer;
+        Indexer::close(database).expect("close");
+
+        // the host died while rebuilding an event store without user version
+        std::fs::create_dir_all(path.join(REINDEX_DIR)).expect("reindex dir");
+        std::fs::write(rebuild_marker(&path), b"").expect("rebuild marker");
+        let error = Indexer::new_in_path(path.clone(), config.clone()).err();
+        assert!(matches!(
+            error,
+            Some(Error::Seshat(SeshatError::ReindexError))
+        ));
+
+        let stop = CancelToken::default();
+        Indexer::reindex(&path, &config, &mut |_, _| (), &stop).expect("reindex");
+        let indexer = Indexer::new_in_path(path, config).expect("reopen");
+        let reply = search(&indexer, "message", json!(null)).expect("search");
+        assert_eq!(reply["count"], 1);
+    }
+
+    #[test]
+    fn reindex_unconvertible_event() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        let events = (1..=3).map(message_event).collect();
+        index_events(&mut indexer, events);
+        // the oldest one, which is loaded last
+        let source = json!({ "type": "m.room.message", "event_id": "$1", "origin_server_ts": 1 });
+        indexer
+            .connection
+            .execute(
+                "UPDATE events SET source = ?1 WHERE event_id = '$1'",
+                &[source.to_string()],
+            )
+            .expect("update");
+        indexer
+            .set_user_version(SetUserVersion { version: 1 })
+            .unwrap();
+        let Indexer {
+            database,
+            path,
+            config,
+            ..
+        } = indexer;
+        Indexer::close(database).expect("close");
+
+        let stop = CancelToken::default();
+        Indexer::reindex(&path, &config, &mut |_, _| (), &stop).expect("reindex");
+        let indexer = Indexer::new_in_path(path, config).expect("reopen");
+        let reply = search(&indexer, "message", json!(null)).expect("search");
+        assert_eq!(reply["count"], 2);
+    }
+
+    #[test]
+    fn search_event_indexes() {
+        let seshat = Seshat::default();
+        let tmpdirs = [tempdir().expect("tempdir"), tempdir().expect("tempdir")];
+        for (event_store, tmpdir, timestamps) in
+            [("a", &tmpdirs[0], &[1, 3][..]), ("b", &tmpdirs[1], &[2])]
+        {
+            let mut indexer = indexer(tmpdir.path());
+            let events = timestamps.iter().map(|ts| message_event(*ts)).collect();
+            index_events(&mut indexer, events);
+            indexers(&seshat).insert(event_store.to_owned(), Arc::new(Mutex::new(indexer)));
+        }
+
+        let cancel = CancelToken::default();
+        let mut next_batch = json!(null);
+        let mut pages = Vec::new();
+        loop {
+            let message = json!({
+                "method": "searchEventIndexes",
                 "content": {
-                    "checkpoint": checkpoint
+                    "term": "message",
+                    "config": { "limit": 1, "order_by_recency": true },
+                    "nextBatch": next_batch,
                 }
-            }),
-        )
-        .expect("addCrawlerCheckpoint");
+            });
+            let reply = handle_message(&seshat, message, &cancel).expect("searchEventIndexes");
+            assert_eq!(reply["count"], 3);
+            let result = &reply["results"][0];
+            pages.push((
+                result["event_store"].clone(),
+                result["result"]["event_id"].clone(),
+            ));
+            next_batch = reply["next_batch"].clone();
+            if next_batch.is_null() {
+                break;
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                (json!("a"), json!("$3")),
+                (json!("b"), json!("$2")),
+                (json!("a"), json!("$1"))
+            ]
+        );
 
-        handle_message(
-            &mut radical,
-            json!({
-                "method": "removeCrawlerCheckpoint",
+        let message = json!({
+            "method": "searchEventIndexes",
+            "content": { "term": "message", "config": {}, "nextBatch": "nope" }
+        });
+        let error = handle_message(&seshat, message, &cancel).unwrap_err();
+        assert_eq!(error.code(), "invalidMessage");
+    }
+
+    #[test]
+    fn search_event_indexes_filtered() {
+        let seshat = Seshat::default();
+        let tmpdirs = [tempdir().expect("tempdir"), tempdir().expect("tempdir")];
+        for (event_store, tmpdir, timestamps) in
+            [("a", &tmpdirs[0], &[1, 3][..]), ("b", &tmpdirs[1], &[2])]
+        {
+            let mut indexer = indexer(tmpdir.path());
+            let events = timestamps.iter().map(|ts| message_event(*ts)).collect();
+            index_events(&mut indexer, events);
+            indexers(&seshat).insert(event_store.to_owned(), Arc::new(Mutex::new(indexer)));
+        }
+
+        let cancel = CancelToken::default();
+        let mut next_batch = json!(null);
+        let mut pages = 0;
+        loop {
+            let message = json!({
+                "method": "searchEventIndexes",
                 "content": {
-                    "oldCheckpoint": checkpoint
+                    "term": "message",
+                    "config": { "limit": 1, "order_by_recency": true },
+                    "filter": { "senders": ["@example2:localhost"] },
+                    "nextBatch": next_batch,
                 }
-            }),
-        )
-        .expect("removeCrawlerCheckpoint");
+            });
+            let reply = handle_message(&seshat, message, &cancel).expect("searchEventIndexes");
+            // counted once, a count on a later page would include the new event
+            assert_eq!(reply["count"], 3);
+            if pages == 0 {
+                // into the event store whose result didn't make it onto the page
+                let indexer = indexers(&seshat).get("b").cloned().expect("b");
+                index_events(&mut lock(&indexer), vec![message_event(4)]);
+            }
+            pages += 1;
+            next_batch = reply["next_batch"].clone();
+            if next_batch.is_null() {
+                break;
+            }
+        }
+        assert!(pages > 1);
+    }
 
-        let checkpoints = handle_message(
-            &mut radical,
-            json!({
-                "method": "loadCheckpoints"
-            }),
-        )
-        .expect("loadCheckpoints");
+    #[test]
+    fn close_while_searching() {
+        let seshat = Arc::new(Seshat::default());
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        index_events(&mut indexer, vec![message_event(1)]);
+        let indexer = Arc::new(Mutex::new(indexer));
+        indexers(&seshat).insert("a".to_owned(), indexer.clone());
+
+        // a search across event stores that's still busy with the store
+        let (locked, searching) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let searcher = thread::spawn(move || {
+            let _indexer = lock(&indexer);
+            locked.send(()).unwrap();
+            let _ = released.recv();
+        });
+        searching.recv().unwrap();
+
+        let close = thread::spawn({
+            let seshat = seshat.clone();
+            move || {
+                let message = json!({ "method": "closeEventIndex", "eventStore": "a" });
+                handle_message(&seshat, message, &CancelToken::default())
+            }
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(indexers(&seshat).is_empty());
+        drop(release);
+        searcher.join().unwrap();
+        close.join().unwrap().expect("closeEventIndex");
+
+        // the database was shut down and can be opened again
+        let indexer = self::indexer(tmpdir.path());
+        let reply = search(&indexer, "message", json!(null)).expect("search");
+        assert_eq!(reply["count"], 1);
+    }
+
+    #[test]
+    fn filtered_search() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        let mut events = Vec::new();
+        for (ts, sender) in [
+            (1, "@bob:localhost"),
+            (2, "@bob:localhost"),
+            (3, "@alice:localhost"),
+            (4, "@bob:localhost"),
+        ] {
+            let mut event = message_event(ts);
+            event["sender"] = json!(sender);
+            events.push(event);
+        }
+        index_events(&mut indexer, events);
+
+        // the first page takes two of seshat's batches and ends in the middle
+        // of the second one
+        let cancel = CancelToken::default();
+        let mut next_batch = json!(null);
+        let mut pages = Vec::new();
+        loop {
+            let message = serde_json::from_value(json!({
+                "term": "message",
+                "config": { "limit": 2, "order_by_recency": true },
+                "filter": { "senders": ["@bob:localhost"] },
+                "nextBatch": next_batch,
+            }))
+            .unwrap();
+            let reply = indexer
+                .search_event_index(message, &cancel)
+                .expect("search");
+            assert_eq!(reply["count"], 3);
+            let results = reply["results"].as_array().unwrap().iter();
+            let page: Vec<Value> = results
+                .map(|result| result["result"]["event_id"].clone())
+                .collect();
+            pages.push(page);
+            next_batch = reply["next_batch"].clone();
+            if next_batch.is_null() {
+                break;
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![vec![json!("$4"), json!("$2")], vec![json!("$1")]]
+        );
+    }
+
+    #[test]
+    fn query_search() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        let mut events = Vec::new();
+        for (id, body, room, ts) in [
+            (1, "Test message", "!a:localhost", 1_577_836_800_000_i64),
+            (
+                2,
+                "Message test spam",
+                "!a:localhost",
+                1_577_836_800_000_i64,
+            ),
+            (3, "Test message", "!b:localhost", 1_577_836_800_000_i64),
+            (4, "Test message", "!a:localhost", 1_546_300_800_000_i64),
+        ] {
+            let mut event = message_event(id);
+            event["content"]["body"] = json!(body);
+            event["room_id"] = json!(room);
+            event["origin_server_ts"] = json!(ts);
+            events.push(event);
+        }
+        index_events(&mut indexer, events);
+
+        let ids = |reply: Value| {
+            let mut ids: Vec<String> = reply["results"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|result| result["result"]["event_id"].as_str().unwrap().to_owned())
+                .collect();
+            ids.sort();
+            ids
+        };
 
-        let reply = handle_message(
-            &mut radical,
+        let term = "\"test message\" in:!a:localhost after:2019-12-31";
+        let reply = search(&indexer, term, json!(null)).expect("search");
+        assert_eq!(reply["highlights"], json!(["message", "test"]));
+        assert_eq!(ids(reply), vec!["$1"]);
+        let message = json!({ "term": "test -spam", "config": { "room_id": "!a:localhost" } });
+        let message = serde_json::from_value(message).expect("message");
+        let reply = indexer.search_event_index(message, &CancelToken::default());
+        assert_eq!(ids(reply.expect("search")), vec!["$1", "$4"]);
+        let reply = search(&indexer, "-spam before:2020-01-01", json!(null));
+        assert_eq!(ids(reply.expect("search")), vec!["$4"]);
+
+        let error = search(&indexer, "test OR", json!(null)).unwrap_err();
+        let error = error.to_reply();
+        assert_eq!(error["code"], "invalidQuery");
+        assert_eq!(error["data"], json!({ "position": 5 }));
+    }
+
+    #[test]
+    fn edits() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        let profile = Profile::new("Alice", "");
+        let message = |ts: i64, body: &str| {
+            let mut event = message_event(ts);
+            event["content"]["body"] = json!(body);
+            event
+        };
+        let edit = |ts: i64, target: &str, body: &str| {
+            let mut event = message(ts, &format!("* {}", body));
+            event["content"]["m.new_content"] = json!({ "body": body, "msgtype": "m.text" });
+            event["content"]["m.relates_to"] =
+                json!({ "rel_type": "m.replace", "event_id": target });
+            event
+        };
+
+        // live, with the edit right after the event
+        index_events(
+            &mut indexer,
+            vec![message(1, "Live mesage"), edit(2, "$1", "Live message")],
+        );
+        // crawled back in time, with the event in a later batch
+        let batches = vec![
+            vec![edit(5, "$3", "Crawled message")],
+            vec![
+                edit(4, "$3", "Crawled massage"),
+                message(3, "Crawled mesage"),
+            ],
+        ];
+        for events in batches {
+            let events: Vec<Value> = events
+                .into_iter()
+                .map(|event| json!({ "event": event, "profile": profile }))
+                .collect();
+            let message = serde_json::from_value(json!({ "events": events })).unwrap();
+            let res = indexer
+                .add_history_events(message)
+                .expect("add_history_events");
+            assert_eq!(res, false);
+        }
+        commit(&mut indexer);
+
+        let search = |term: &str| {
+            let reply = search(&indexer, term, json!(null));
+            let mut results = reply.expect("search")["results"]
+                .as_array()
+                .unwrap()
+                .clone();
+            results.sort_by_key(|result| result["result"]["event_id"].as_str().map(str::to_owned));
+            results
+        };
+        assert!(search("mesage").is_empty());
+        assert!(search("massage").is_empty());
+        let results = search("message");
+        let events: Vec<&Value> = results.iter().map(|result| &result["result"]).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event_id"], "$1");
+        assert_eq!(events[0]["content"]["body"], "Live message");
+        assert_eq!(events[1]["event_id"], "$3");
+        assert_eq!(events[1]["content"]["body"], "Crawled message");
+        assert_eq!(
+            events[1]["unsigned"]["m.relations"]["m.replace"]["event_id"],
+            "$5"
+        );
+    }
+
+    #[test]
+    fn redactions() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        let profile = Profile::new("Alice", "");
+        let message = |ts: i64| json!({ "event": message_event(ts), "profile": profile });
+        let redaction = |event_id: &str, redacts: &str| {
             json!({
-                "method": "getStats"
-            }),
-        )
-        .expect("getStats");
+                "event": {
+                    "type": "m.room.redaction",
+                    "room_id": "!FDVbSkWZSIcwvBFMdt:localhost",
+                    "sender": "@example2:localhost",
+                    "origin_server_ts": 1_580_728_702_628_i64,
+                    "event_id": event_id,
+                    "redacts": redacts,
+                    "content": {},
+                },
+                "profile": profile,
+            })
+        };
 
-        assert_eq!(checkpoints.as_array().expect("checkpoints").len(), 0);
-        assert_eq!(reply["eventCount"].as_i64().expect("eventCount"), 1);
+        // live, with the redaction right after the event
+        let events = [message(1), redaction("$r1", "$1")];
+        let events = events.iter().map(|event| event["event"].clone()).collect();
+        index_events(&mut indexer, events);
+        // live, but not committed yet when the crawler finds its redaction
+        let payload = AddEventToIndex {
+            ev: message(6)["event"].clone(),
+            profile: Profile::new("Alice", ""),
+        };
+        indexer.add_event_to_index(payload).expect("add_event");
+        // crawled back in time, with the event in a later batch
+        let mut redacted = message(4);
+        redacted["event"]["unsigned"] = json!({ "redacted_because": {} });
+        redacted["event"]["content"] = json!({});
+        let batches = vec![
+            vec![redaction("$r6", "$6"), redaction("$r3", "$3")],
+            vec![message(2), message(3), redacted],
+            vec![redaction("$r5", "$5"), message(5)],
+        ];
+        for events in batches {
+            let message = serde_json::from_value(json!({ "events": events })).unwrap();
+            let res = indexer
+                .add_history_events(message)
+                .expect("add_history_events");
+            assert_eq!(res, false);
+        }
+        // known redactions don't keep the crawl going
+        let events = json!([message(2), redaction("$r3", "$3")]);
+        let message = serde_json::from_value(json!({ "events": events }));
+        let res = indexer.add_history_events(message.unwrap());
+        assert_eq!(res.expect("add_history_events"), true);
+
+        commit(&mut indexer);
+        let reply = search(&indexer, "message", json!(null)).expect("search");
+        assert_eq!(reply["count"], 1);
+        assert_eq!(reply["results"][0]["result"]["event_id"], "$2");
+    }
+
+    #[test]
+    fn file_events() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        let mut events = Vec::new();
+        for (ts, msgtype, content) in [
+            (1, "m.text", json!({ "body": "Test message" })),
+            (
+                2,
+                "m.image",
+                json!({ "body": "Beach at sunset", "filename": "holiday.jpg",
+                        "info": { "mimetype": "image/jpeg", "size": 2048 } }),
+            ),
+            (
+                3,
+                "m.file",
+                json!({ "body": "report.pdf",
+                        "info": { "mimetype": "application/pdf", "size": 4096 } }),
+            ),
+            (
+                4,
+                "m.image",
+                json!({ "body": "cat.png",
+                        "info": { "mimetype": "image/png", "size": 512 } }),
+            ),
+        ] {
+            let mut event = message_event(ts);
+            event["content"] = content;
+            event["content"]["msgtype"] = json!(msgtype);
+            events.push(event);
+        }
+        index_events(&mut indexer, events);
+
+        for term in &["holiday", "sunset", "report"] {
+            let reply = search(&indexer, term, json!(null));
+            assert_eq!(reply.expect("search")["count"], 1, "{}", term);
+        }
+
+        let load = |filter: Value| {
+            let mut message = json!({ "roomId": "!FDVbSkWZSIcwvBFMdt:localhost", "limit": 1 });
+            message
+                .as_object_mut()
+                .unwrap()
+                .extend(filter.as_object().unwrap().clone());
+            let reply = indexer.load_file_events(serde_json::from_value(message).unwrap());
+            let reply = reply.expect("load_file_events");
+            let events = reply.as_array().unwrap().iter();
+            events
+                .map(|file| file["event"]["event_id"].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(load(json!({})), vec![json!("$4")]);
+        assert_eq!(
+            load(json!({ "mimetypes": ["image/jpeg"] })),
+            vec![json!("$2")]
+        );
+        assert_eq!(
+            load(json!({ "minSize": 1024, "limit": 5 })),
+            vec![json!("$3"), json!("$2")]
+        );
+        assert_eq!(
+            load(json!({ "mimetypes": ["video/*"] })),
+            Vec::<Value>::new()
+        );
+
+        // file names survive a reindex
+        indexer
+            .set_user_version(SetUserVersion { version: 1 })
+            .unwrap();
+        indexer
+            .database
+            .shutdown()
+            .recv()
+            .unwrap()
+            .expect("shutdown");
+        let config = Config::new().set_passphrase("TEST_PASS");
+        let path = tmpdir.path().to_path_buf();
+        let stop = CancelToken::default();
+        Indexer::reindex(&path, &config, &mut |_, _| (), &stop).expect("reindex");
+        let indexer = self::indexer(tmpdir.path());
+        let reply = search(&indexer, "holiday", json!(null));
+        assert_eq!(reply.expect("search")["count"], 1);
+    }
+
+    #[test]
+    fn formatted_body() {
+        let tmpdir = tempdir().expect("tempdir");
+        let settings = StoreSettings {
+            language: None,
+            formatted_body: true,
+        };
+        settings.save(tmpdir.path()).expect("save");
+        let mut indexer = indexer(tmpdir.path());
+        let mut event = event_room_message_text();
+        event["content"] = json!({
+            "msgtype": "m.text",
+            "body": "> <@alice:localhost> quoted text\n\nsee https://example.org",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "<mx-reply><blockquote>quoted text</blockquote></mx-reply>\
+                               see <a href=\"https://example.org\">the docs</a>",
+        });
+        index_events(&mut indexer, vec![event]);
+
+        for (term, count) in &[("docs", 1), ("quoted", 0)] {
+            let reply = search(&indexer, term, json!(null));
+            assert_eq!(reply.expect("search")["count"], *count, "{}", term);
+        }
+    }
+
+    #[test]
+    fn formatted_body_change() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        let mut event = event_room_message_text();
+        event["content"] = json!({
+            "msgtype": "m.text",
+            "body": "see https://example.org",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "see <a href=\"https://example.org\">the docs</a>",
+        });
+        index_events(&mut indexer, vec![event]);
+        let Indexer {
+            database,
+            path,
+            config,
+            ..
+        } = indexer;
+        Indexer::close(database).expect("close");
+
+        // the same value leaves the index alone
+        Indexer::set_formatted_body(&path, false).expect("formatted body");
+        assert!(!rebuild_marker(&path).exists());
+
+        Indexer::set_formatted_body(&path, true).expect("formatted body");
+        let error = Indexer::new_in_path(path.clone(), config.clone()).err();
+        assert!(matches!(
+            error,
+            Some(Error::Seshat(SeshatError::ReindexError))
+        ));
+        let stop = CancelToken::default();
+        Indexer::reindex(&path, &config, &mut |_, _| (), &stop).expect("reindex");
+        let indexer = Indexer::new_in_path(path, config).expect("reopen");
+        let reply = search(&indexer, "docs", json!(null)).expect("search");
+        assert_eq!(reply["count"], 1);
+    }
+
+    #[test]
+    fn replies() {
+        let tmpdir = tempdir().expect("tempdir");
+        let mut indexer = indexer(tmpdir.path());
+        let mut reply = message_event(1);
+        reply["content"] = json!({
+            "msgtype": "m.text",
+            "body": "> <@alice:localhost> quoted question\n\nthe answer to the question",
+            "m.relates_to": { "m.in_reply_to": { "event_id": "$question" } },
+        });
+        let mut quote = message_event(2);
+        quote["content"] = json!({ "msgtype": "m.text", "body": "> quoted text\n\nno reply" });
+        index_events(&mut indexer, vec![reply, quote]);
+
+        let searches = [
+            ("quoted", json!(null), 1),
+            ("answer", json!(null), 1),
+            ("answer", json!({ "replies": false }), 0),
+            ("answer", json!({ "replies": true }), 1),
+            ("quoted", json!({ "replies": true }), 0),
+        ];
+        for (term, filter, count) in &searches {
+            let reply = search(&indexer, term, filter.clone());
+            let count = json!(count);
+            assert_eq!(
+                reply.expect("search")["count"],
+                count,
+                "{} {}",
+                term,
+                filter
+            );
+        }
+
+        // snippets leave the quote out as well
+        let message = json!({ "term": "question", "config": {}, "snippetWidth": 12 });
+        let message = serde_json::from_value(message).expect("message");
+        let reply = indexer.search_event_index(message, &CancelToken::default());
+        assert_eq!(
+            reply.expect("search")["results"][0]["snippets"],
+            json!([{ "offset": 14, "text": "the question", "matches": [[4, 12]] }])
+        );
     }
 }
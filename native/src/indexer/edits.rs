@@ -0,0 +1,189 @@
+//! Edits, i.e. messages with an `m.replace` relation. They aren't indexed
+//! themselves, their `m.new_content` replaces the content of the event they
+//! point at instead, so search finds the current text under the original
+//! event ID.
+
+use serde_json::{json, Value};
+use seshat::{Connection, Error as SeshatError};
+
+use crate::error::Result;
+
+/// What became of an edit.
+pub enum Edited {
+    /// The edited event, to be added in place of the one in the store.
+    Replaced(Value),
+    /// The event isn't indexed yet, the edit is applied once it is.
+    Pending,
+    /// A newer edit was applied already, or the edit isn't valid.
+    Outdated,
+}
+
+/// The ID of the event an edit replaces, `None` if it isn't an edit.
+pub fn target(event: &Value) -> Option<&str> {
+    let relation = &event["content"]["m.relates_to"];
+    if event["type"] != "m.room.message" || relation["rel_type"] != "m.replace" {
+        return None;
+    }
+    relation["event_id"].as_str()
+}
+
+/// Puts the edit's new content into the event, unless a newer edit was
+/// applied already. Only the sender of an event can edit it.
+///
+/// The edit is recorded the way servers bundle it into `unsigned`, so clients
+/// can tell that the event was edited.
+pub fn apply(event: &mut Value, edit: &Value) -> bool {
+    let new_content = &edit["content"]["m.new_content"];
+    if !new_content["body"].is_string() || edit["sender"] != event["sender"] {
+        return false;
+    }
+    let ts = edit["origin_server_ts"].as_i64().unwrap_or_default();
+    let applied = &event["unsigned"]["m.relations"]["m.replace"]["origin_server_ts"];
+    if applied.as_i64().map_or(false, |applied| applied >= ts) {
+        return false;
+    }
+
+    // replies stay replies
+    let relation = event["content"]["m.relates_to"].take();
+    event["content"] = new_content.clone();
+    if !relation.is_null() {
+        event["content"]["m.relates_to"] = relation;
+    }
+
+    let unsigned = &mut event["unsigned"];
+    if !unsigned.is_object() {
+        *unsigned = json!({});
+    }
+    let relations = &mut unsigned["m.relations"];
+    if !relations.is_object() {
+        *relations = json!({});
+    }
+    relations["m.replace"] = json!({
+        "event_id": edit["event_id"],
+        "origin_server_ts": ts,
+        "sender": edit["sender"],
+    });
+    true
+}
+
+/// Edits waiting for their event live in seshat's database, which is the
+/// only encrypted place we have.
+pub fn create_table(connection: &Connection) -> Result<()> {
+    connection
+        .execute_batch(
+            "CREATE TABLE IF NOT EXISTS pending_edits (
+                event_id TEXT NOT NULL PRIMARY KEY,
+                server_ts INTEGER NOT NULL,
+                source TEXT NOT NULL
+            )",
+        )
+        .map_err(SeshatError::from)?;
+    Ok(())
+}
+
+/// Keeps the edit until its event is added, only the newest one per event.
+pub fn save_pending(connection: &Connection, target: &str, edit: &Value) -> Result<()> {
+    let ts = edit["origin_server_ts"].as_i64().unwrap_or_default();
+    connection
+        .execute(
+            "INSERT INTO pending_edits (event_id, server_ts, source) VALUES (?1, ?2, ?3)
+             ON CONFLICT (event_id) DO UPDATE
+             SET server_ts = excluded.server_ts, source = excluded.source
+             WHERE excluded.server_ts > pending_edits.server_ts",
+            &[target, &ts.to_string(), &edit.to_string()],
+        )
+        .map_err(SeshatError::from)?;
+    Ok(())
+}
+
+/// Removes and returns the edit waiting for the event, if any.
+pub fn take_pending(connection: &Connection, event_id: &str) -> Result<Option<Value>> {
+    let edit = query_source(
+        connection,
+        "SELECT source FROM pending_edits WHERE event_id = ?1",
+        event_id,
+    )?;
+    if edit.is_some() {
+        connection
+            .execute("DELETE FROM pending_edits WHERE event_id = ?1", &[event_id])
+            .map_err(SeshatError::from)?;
+    }
+    Ok(edit)
+}
+
+/// The source of an event in the store.
+pub fn load_source(connection: &Connection, event_id: &str) -> Result<Option<Value>> {
+    query_source(
+        connection,
+        "SELECT source FROM events WHERE event_id = ?1",
+        event_id,
+    )
+}
+
+fn query_source(connection: &Connection, sql: &str, event_id: &str) -> Result<Option<Value>> {
+    let mut statement = connection.prepare(sql).map_err(SeshatError::from)?;
+    let mut sources = statement
+        .query_map(&[event_id], |row| row.get::<_, String>(0))
+        .map_err(SeshatError::from)?;
+    match sources.next() {
+        Some(source) => Ok(Some(serde_json::from_str(
+            &source.map_err(SeshatError::from)?,
+        )?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(body: &str, ts: i64) -> Value {
+        json!({
+            "type": "m.room.message",
+            "sender": "@alice:localhost",
+            "event_id": format!("$edit{}", ts),
+            "origin_server_ts": ts,
+            "content": {
+                "body": format!("* {}", body),
+                "msgtype": "m.text",
+                "m.new_content": { "body": body, "msgtype": "m.text" },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$original" },
+            },
+        })
+    }
+
+    #[test]
+    fn apply_newest() {
+        let mut event = json!({
+            "type": "m.room.message",
+            "sender": "@alice:localhost",
+            "event_id": "$original",
+            "origin_server_ts": 1,
+            "content": {
+                "body": "helo",
+                "msgtype": "m.text",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$question" } },
+            },
+        });
+        assert_eq!(target(&edit("hello", 3)), Some("$original"));
+        assert_eq!(target(&event), None);
+
+        assert!(apply(&mut event, &edit("hello", 3)));
+        // edits can arrive out of order while crawling
+        assert!(!apply(&mut event, &edit("hell", 2)));
+        assert_eq!(event["content"]["body"], "hello");
+        assert_eq!(
+            event["content"]["m.relates_to"]["m.in_reply_to"]["event_id"],
+            "$question"
+        );
+        assert_eq!(
+            event["unsigned"]["m.relations"]["m.replace"],
+            json!({ "event_id": "$edit3", "origin_server_ts": 3, "sender": "@alice:localhost" })
+        );
+
+        let mut other = edit("spam", 4);
+        other["sender"] = json!("@mallory:localhost");
+        assert!(!apply(&mut event, &other));
+        assert_eq!(event["content"]["body"], "hello");
+    }
+}
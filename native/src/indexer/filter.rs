@@ -0,0 +1,203 @@
+//! Filters for search results, on the fields seshat doesn't index.
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Narrows down search results, every given field has to match. Lists match
+/// if any of their entries does.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchFilter {
+    pub senders: Option<Vec<String>>,
+    pub rooms: Option<Vec<String>>,
+    /// Event types, e.g. `m.room.message`.
+    pub types: Option<Vec<String>>,
+    pub msgtypes: Option<Vec<String>>,
+    /// Lower bound of `origin_server_ts`, inclusive.
+    pub from_ts: Option<i64>,
+    /// Upper bound of `origin_server_ts`, inclusive.
+    pub to_ts: Option<i64>,
+    /// Only replies if `true`, no replies if `false`.
+    pub replies: Option<bool>,
+}
+
+impl SearchFilter {
+    pub fn matches(&self, event: &Value) -> bool {
+        let ts = event["origin_server_ts"].as_i64().unwrap_or_default();
+        any_of(&self.senders, &event["sender"])
+            && any_of(&self.rooms, &event["room_id"])
+            && any_of(&self.types, &event["type"])
+            && any_of(&self.msgtypes, &event["content"]["msgtype"])
+            && self.from_ts.map_or(true, |from_ts| ts >= from_ts)
+            && self.to_ts.map_or(true, |to_ts| ts <= to_ts)
+            && self
+                .replies
+                .map_or(true, |replies| replies == is_reply(event))
+    }
+
+    /// A filter that only lets through what both of them do.
+    pub fn and(self, other: SearchFilter) -> SearchFilter {
+        SearchFilter {
+            senders: both(self.senders, other.senders),
+            rooms: both(self.rooms, other.rooms),
+            types: both(self.types, other.types),
+            msgtypes: both(self.msgtypes, other.msgtypes),
+            from_ts: self.from_ts.max(other.from_ts),
+            to_ts: match (self.to_ts, other.to_ts) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            // can't be both, so the one given first wins
+            replies: self.replies.or(other.replies),
+        }
+    }
+}
+
+/// Narrows down file events, every given field has to match.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileFilter {
+    /// `info.mimetype`, e.g. `image/png`, or `image/*` for all images.
+    pub mimetypes: Option<Vec<String>>,
+    /// Lower bound of `info.size` in bytes, inclusive.
+    pub min_size: Option<u64>,
+    /// Upper bound of `info.size` in bytes, inclusive.
+    pub max_size: Option<u64>,
+}
+
+impl FileFilter {
+    pub fn matches(&self, event: &Value) -> bool {
+        let info = &event["content"]["info"];
+        let mimetype = info["mimetype"].as_str().unwrap_or_default();
+        let size = info["size"].as_u64();
+        let mimetype_matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(prefix) => mimetype.split('/').next() == Some(prefix),
+            None => mimetype == pattern,
+        };
+        self.mimetypes
+            .as_ref()
+            .map_or(true, |mimetypes| mimetypes.iter().any(mimetype_matches))
+            && self
+                .min_size
+                .map_or(true, |min_size| size.map_or(false, |size| size >= min_size))
+            && self
+                .max_size
+                .map_or(true, |max_size| size.map_or(false, |size| size <= max_size))
+    }
+}
+
+fn any_of(values: &Option<Vec<String>>, value: &Value) -> bool {
+    match values {
+        Some(values) => value
+            .as_str()
+            .map_or(false, |value| values.iter().any(|v| v == value)),
+        None => true,
+    }
+}
+
+fn is_reply(event: &Value) -> bool {
+    event["content"]["m.relates_to"]["m.in_reply_to"]["event_id"].is_string()
+}
+
+fn both(a: Option<Vec<String>>, b: Option<Vec<String>>) -> Option<Vec<String>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.into_iter().filter(|value| b.contains(value)).collect()),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn matches() {
+        let event = json!({
+            "type": "m.room.message",
+            "room_id": "!FDVbSkWZSIcwvBFMdt:localhost",
+            "sender": "@bob:localhost",
+            "content": { "body": "holiday.jpg", "msgtype": "m.image" },
+            "origin_server_ts": 1_580_728_702_628_i64,
+        });
+        let filter = |filter: Value| -> SearchFilter { serde_json::from_value(filter).unwrap() };
+
+        assert!(filter(json!({})).matches(&event));
+        assert!(filter(json!({
+            "senders": ["@alice:localhost", "@bob:localhost"],
+            "rooms": ["!FDVbSkWZSIcwvBFMdt:localhost"],
+            "types": ["m.room.message"],
+            "msgtypes": ["m.image", "m.file"],
+            "fromTs": 1_580_728_702_628_i64,
+            "toTs": 1_580_728_702_628_i64,
+        }))
+        .matches(&event));
+
+        assert!(!filter(json!({ "senders": ["@alice:localhost"] })).matches(&event));
+        assert!(!filter(json!({ "rooms": [] })).matches(&event));
+        assert!(!filter(json!({ "types": ["m.room.topic"] })).matches(&event));
+        assert!(!filter(json!({ "msgtypes": ["m.text"] })).matches(&event));
+        assert!(!filter(json!({ "fromTs": 1_580_728_702_629_i64 })).matches(&event));
+        assert!(!filter(json!({ "toTs": 1_580_728_702_627_i64 })).matches(&event));
+        assert!(filter(json!({ "replies": false })).matches(&event));
+        assert!(!filter(json!({ "replies": true })).matches(&event));
+
+        let mut reply = event;
+        reply["content"]["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": "$1" } });
+        assert!(filter(json!({ "replies": true })).matches(&reply));
+        assert!(!filter(json!({ "replies": false })).matches(&reply));
+    }
+
+    #[test]
+    fn file_matches() {
+        let event = json!({
+            "type": "m.room.message",
+            "content": {
+                "body": "holiday.jpg",
+                "msgtype": "m.image",
+                "info": { "mimetype": "image/jpeg", "size": 2048 },
+            },
+        });
+        let filter = |filter: Value| -> FileFilter { serde_json::from_value(filter).unwrap() };
+
+        assert!(filter(json!({})).matches(&event));
+        assert!(filter(json!({
+            "mimetypes": ["application/pdf", "image/jpeg"],
+            "minSize": 2048,
+            "maxSize": 2048,
+        }))
+        .matches(&event));
+        assert!(filter(json!({ "mimetypes": ["image/*"] })).matches(&event));
+
+        assert!(!filter(json!({ "mimetypes": ["video/*"] })).matches(&event));
+        assert!(!filter(json!({ "mimetypes": ["image/png"] })).matches(&event));
+        assert!(!filter(json!({ "minSize": 2049 })).matches(&event));
+        assert!(!filter(json!({ "maxSize": 2047 })).matches(&event));
+        // without a size it can't be within bounds
+        assert!(!filter(json!({ "maxSize": 2047 })).matches(&json!({})));
+    }
+
+    #[test]
+    fn and() {
+        let a: SearchFilter = serde_json::from_value(json!({
+            "senders": ["@alice:localhost", "@bob:localhost"],
+            "fromTs": 10,
+            "toTs": 20,
+        }))
+        .unwrap();
+        let b: SearchFilter = serde_json::from_value(json!({
+            "senders": ["@bob:localhost"],
+            "rooms": ["!FDVbSkWZSIcwvBFMdt:localhost"],
+            "fromTs": 15,
+        }))
+        .unwrap();
+        let filter = a.and(b);
+        assert_eq!(filter.senders, Some(vec!["@bob:localhost".to_owned()]));
+        assert_eq!(
+            filter.rooms,
+            Some(vec!["!FDVbSkWZSIcwvBFMdt:localhost".to_owned()])
+        );
+        assert_eq!(filter.types, None);
+        assert_eq!((filter.from_ts, filter.to_ts), (Some(15), Some(20)));
+    }
+}
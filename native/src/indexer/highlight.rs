@@ -0,0 +1,282 @@
+//! Words to highlight in search results, the way the server's `/search` API
+//! returns them, and snippets of the text around them.
+
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+use seshat::{Config, Language};
+use std::collections::{BTreeSet, HashSet};
+
+use super::LANGUAGES;
+
+/// Operators of the query syntax, which aren't searched for.
+const OPERATORS: &[&str] = &["AND", "OR", "NOT"];
+
+/// A window of a message body around one or more matches. Offsets count
+/// characters, not bytes.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Snippet {
+    /// Where the snippet starts in the body, or for replies in the body
+    /// without the quote of the message replied to.
+    pub offset: usize,
+    pub text: String,
+    /// `[start, end)` of every match within `text`.
+    pub matches: Vec<[usize; 2]>,
+}
+
+/// Collects the query terms and every word of the matched events that only
+/// matched because of stemming, e.g. `messages` for the term `message`.
+pub struct Highlighter {
+    stemmer: Option<Stemmer>,
+    /// Whether terms match within words, as they do for languages that
+    /// aren't split into words, i.e. japanese.
+    within_words: bool,
+    stems: HashSet<String>,
+    highlights: BTreeSet<String>,
+}
+
+impl Highlighter {
+    pub fn new(language: &Language, query: &str) -> Highlighter {
+        let stemmer = algorithm(language).map(Stemmer::create);
+        let mut highlighter = Highlighter {
+            stemmer,
+            within_words: *language == Language::Japanese,
+            stems: HashSet::new(),
+            highlights: BTreeSet::new(),
+        };
+        for term in query_terms(query) {
+            let stem = highlighter.stem(&term);
+            highlighter.stems.insert(stem);
+            highlighter.highlights.insert(term);
+        }
+        highlighter
+    }
+
+    /// Snippets of `width` characters around the matches in `text`, windows
+    /// that would overlap are merged into one.
+    pub fn snippets(&self, text: &str, width: usize) -> Vec<Snippet> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut snippets: Vec<Snippet> = Vec::new();
+        for [start, end] in self.matches(&chars) {
+            // centered on the match, as far as the text allows
+            let len = end - start;
+            let offset = start
+                .saturating_sub(width.saturating_sub(len) / 2)
+                .min(chars.len().saturating_sub(width));
+            let snippet_end = (offset + width).max(end).min(chars.len());
+
+            if let Some(snippet) = snippets.last_mut() {
+                let previous_end = snippet.offset + snippet.text.chars().count();
+                if offset < previous_end {
+                    let rest = previous_end.min(snippet_end)..snippet_end;
+                    snippet.text.extend(&chars[rest]);
+                    let offset = snippet.offset;
+                    snippet.matches.push([start - offset, end - offset]);
+                    continue;
+                }
+            }
+
+            snippets.push(Snippet {
+                offset,
+                text: chars[offset..snippet_end].iter().collect(),
+                matches: vec![[start - offset, end - offset]],
+            });
+        }
+        snippets
+    }
+
+    /// Adds the words of a matched event's text that stem like a query term.
+    pub fn add_matches(&mut self, text: &str) {
+        // without stemming only the query terms themselves match
+        if self.stemmer.is_none() {
+            return;
+        }
+        for word in words(text) {
+            if self.stems.contains(&self.stem(&word.to_lowercase())) {
+                self.highlights.insert(word.to_owned());
+            }
+        }
+    }
+
+    pub fn highlights(self) -> Vec<String> {
+        self.highlights.into_iter().collect()
+    }
+
+    /// `[start, end)` of the words that stem like a query term, or in
+    /// japanese of the query terms wherever they show up.
+    fn matches(&self, chars: &[char]) -> Vec<[usize; 2]> {
+        // lowercased one by one, so that offsets stay the same
+        let lower: Vec<char> = chars
+            .iter()
+            .map(|c| c.to_lowercase().next().unwrap_or(*c))
+            .collect();
+
+        let mut matches = Vec::new();
+        if self.within_words {
+            let mut start = 0;
+            while start < lower.len() {
+                let term = self.highlights.iter().find(|term| {
+                    let term: Vec<char> = term.chars().collect();
+                    lower[start..].starts_with(&term)
+                });
+                match term {
+                    Some(term) => {
+                        let end = start + term.chars().count();
+                        matches.push([start, end]);
+                        start = end;
+                    }
+                    None => start += 1,
+                }
+            }
+            return matches;
+        }
+
+        let mut start = 0;
+        while start < lower.len() {
+            if !lower[start].is_alphanumeric() {
+                start += 1;
+                continue;
+            }
+            let end = lower[start..]
+                .iter()
+                .position(|c| !c.is_alphanumeric())
+                .map_or(lower.len(), |len| start + len);
+            let word: String = lower[start..end].iter().collect();
+            if self.stems.contains(&self.stem(&word)) {
+                matches.push([start, end]);
+            }
+            start = end;
+        }
+        matches
+    }
+
+    fn stem(&self, word: &str) -> String {
+        match &self.stemmer {
+            Some(stemmer) => stemmer.stem(word).into_owned(),
+            None => word.to_owned(),
+        }
+    }
+}
+
+/// The language a config was made with, seshat keeps it to itself.
+pub fn language(config: &Config) -> Language {
+    LANGUAGES
+        .iter()
+        .map(|language| Language::from(*language))
+        .find(|language| config.clone().set_language(language) == *config)
+        .unwrap_or(Language::Unknown)
+}
+
+fn algorithm(language: &Language) -> Option<Algorithm> {
+    let algorithm = match language {
+        Language::Arabic => Algorithm::Arabic,
+        Language::Danish => Algorithm::Danish,
+        Language::Dutch => Algorithm::Dutch,
+        Language::English => Algorithm::English,
+        Language::Finnish => Algorithm::Finnish,
+        Language::French => Algorithm::French,
+        Language::German => Algorithm::German,
+        Language::Greek => Algorithm::Greek,
+        Language::Hungarian => Algorithm::Hungarian,
+        Language::Italian => Algorithm::Italian,
+        Language::Portuguese => Algorithm::Portuguese,
+        Language::Romanian => Algorithm::Romanian,
+        Language::Russian => Algorithm::Russian,
+        Language::Spanish => Algorithm::Spanish,
+        Language::Swedish => Algorithm::Swedish,
+        Language::Tamil => Algorithm::Tamil,
+        Language::Turkish => Algorithm::Turkish,
+        Language::Japanese | Language::Unknown => return None,
+    };
+    Some(algorithm)
+}
+
+/// Lowercased words of the query, leaving out operators and excluded terms.
+fn query_terms(query: &str) -> impl Iterator<Item = String> + '_ {
+    query
+        .split_whitespace()
+        .filter(|term| !OPERATORS.contains(term) && !term.starts_with('-'))
+        .flat_map(words)
+        .map(str::to_lowercase)
+}
+
+// split like seshat's tokenizer does
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stemmed_matches() {
+        let mut highlighter = Highlighter::new(&Language::English, "Message AND test -spam");
+        highlighter.add_matches("Two Messages, tested: spam");
+        assert_eq!(
+            highlighter.highlights(),
+            vec!["Messages", "message", "test", "tested"]
+        );
+
+        let mut highlighter = Highlighter::new(&Language::Unknown, "message");
+        highlighter.add_matches("Two messages");
+        assert_eq!(highlighter.highlights(), vec!["message"]);
+    }
+
+    #[test]
+    fn snippets() {
+        let highlighter = Highlighter::new(&Language::English, "message");
+        let text = "Some messages are long, the message we are looking for is at the end";
+        let snippets = highlighter.snippets(text, 20);
+        assert_eq!(
+            snippets,
+            vec![
+                Snippet {
+                    offset: 0,
+                    text: "Some messages are lo".to_owned(),
+                    matches: vec![[5, 13]],
+                },
+                Snippet {
+                    offset: 22,
+                    text: ", the message we are".to_owned(),
+                    matches: vec![[6, 13]],
+                }
+            ]
+        );
+
+        let snippets = highlighter.snippets(text, 40);
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].matches, vec![[5, 13], [28, 35]]);
+
+        // the window of a match running past the previous one is added on
+        let snippets = highlighter.snippets("one message message two", 10);
+        assert_eq!(
+            snippets,
+            vec![Snippet {
+                offset: 3,
+                text: " message message t".to_owned(),
+                matches: vec![[1, 8], [9, 16]],
+            }]
+        );
+
+        // without a language only whole words match
+        let highlighter = Highlighter::new(&Language::Unknown, "cat");
+        let snippets = highlighter.snippets("concatenate the cat", 20);
+        assert_eq!(snippets[0].matches, vec![[16, 19]]);
+
+        // in japanese terms match within words
+        let highlighter = Highlighter::new(&Language::Japanese, "伝説");
+        let snippets = highlighter.snippets("ムーミンの伝説の物語", 4);
+        assert_eq!(snippets[0].text, "の伝説の");
+        assert_eq!(snippets[0].offset, 4);
+        assert_eq!(snippets[0].matches, vec![[1, 3]]);
+    }
+
+    #[test]
+    fn config_language() {
+        let config = Config::new().set_passphrase("TEST_PASS");
+        assert_eq!(language(&config), Language::Unknown);
+        let config = config.set_language(&Language::German);
+        assert_eq!(language(&config), Language::German);
+    }
+}
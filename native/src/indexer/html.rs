@@ -0,0 +1,179 @@
+//! Plain text from the HTML of `formatted_body`, for indexing. Tags are
+//! dropped, block elements end up on lines of their own so their words don't
+//! run together, and reply fallbacks (`<mx-reply>`) are left out entirely.
+
+/// Elements whose content isn't text of the message.
+const SKIPPED: &[&str] = &["mx-reply", "script", "style"];
+
+/// Elements that start on a new line.
+const BLOCKS: &[&str] = &[
+    "blockquote",
+    "br",
+    "caption",
+    "details",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "summary",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+pub fn to_text(html: &str) -> String {
+    let mut text = String::new();
+    // nesting of skipped elements we're in
+    let mut skipped = 0_usize;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        if skipped == 0 {
+            text.push_str(&decode_entities(&rest[..start]));
+        }
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let end = match tag_end(rest) {
+            Some(end) => end,
+            // a lone `<` is text
+            None => {
+                if skipped == 0 {
+                    text.push('<');
+                }
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        if SKIPPED.contains(&name.as_str()) {
+            if closing {
+                skipped = skipped.saturating_sub(1);
+            } else if !tag.ends_with('/') {
+                skipped += 1;
+            }
+        } else if skipped == 0 && BLOCKS.contains(&name.as_str()) && !text.ends_with('\n') {
+            text.push('\n');
+        }
+    }
+    if skipped == 0 {
+        text.push_str(&decode_entities(rest));
+    }
+
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines.join("\n")
+}
+
+/// Where the tag at the start of `html` ends, `>` in attribute values aside.
+fn tag_end(html: &str) -> Option<usize> {
+    // `<` followed by anything but a name, `/` or `!` isn't a tag
+    let next = html[1..].chars().next()?;
+    if !(next.is_ascii_alphabetic() || next == '/' || next == '!') {
+        return None;
+    }
+    let mut quote = None;
+    for (i, c) in html.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|len| *len <= 10)
+            .and_then(|len| decode_entity(&rest[1..len + 1]).map(|c| (c, len + 2)));
+        match entity {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    let c = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ => {
+            let number = entity.strip_prefix('#')?;
+            let code = match number
+                .strip_prefix('x')
+                .or_else(|| number.strip_prefix('X'))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            return std::char::from_u32(code);
+        }
+    };
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        let html =
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/!room/$event\">In reply to</a> \
+                    <a href=\"https://matrix.to/#/@alice:localhost\">@alice:localhost</a><br>\
+                    quoted text</blockquote></mx-reply>\
+                    See <a href=\"https://example.org?a=1&amp;b=>\">the docs</a>:<ul><li>one</li>\
+                    <li>two&nbsp;&amp;&#32;three</li></ul><pre><code>fn main() {}\n</code></pre>\
+                    <!-- <p>hidden</p> -->1 &lt; 2 &unknown; <3";
+        assert_eq!(
+            to_text(html),
+            "See the docs:\none\ntwo & three\nfn main() {}\n1 < 2 &unknown; <3"
+        );
+        assert_eq!(to_text("<b>bold</b><i>italic</i>"), "bolditalic");
+        assert_eq!(to_text("<p>unclosed <b"), "unclosed <b");
+    }
+}
@@ -0,0 +1,390 @@
+//! The search syntax: words, `"exact phrases"`, `AND`, `OR`, excluding with
+//! `-` or `NOT` and the filters `from:@alice:example.org`, `in:!room:example.org`,
+//! `before:2020-01-01` and `after:2020-01-01`.
+
+use super::filter::SearchFilter;
+use crate::error::{Error, Result};
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// A parsed search term, split into the full-text query for seshat and the
+/// filters for the results.
+#[derive(Debug)]
+pub struct Query {
+    /// The full-text part in tantivy's syntax.
+    pub text: String,
+    /// Words and phrases that are searched for, not the excluded ones.
+    pub terms: Vec<String>,
+    pub filter: Option<SearchFilter>,
+    /// No syntax was used, so the term can be handed to seshat as it is.
+    pub plain: bool,
+}
+
+enum Clause {
+    Text { text: String, excluded: bool },
+    Operator { operator: String, position: usize },
+}
+
+impl Query {
+    /// Errors point at the character of `query` where the problem starts.
+    pub fn parse(query: &str) -> Result<Query> {
+        let chars: Vec<char> = query.chars().collect();
+        let mut clauses = Vec::new();
+        let mut filter: Option<SearchFilter> = None;
+        let mut plain = true;
+        // position of a `-` or `NOT` waiting for its term
+        let mut excluded: Option<usize> = None;
+
+        let mut position = 0;
+        while position < chars.len() {
+            let start = position;
+            if chars[start].is_whitespace() {
+                position += 1;
+                continue;
+            }
+
+            if chars[start] == '"' {
+                let len = chars[start + 1..]
+                    .iter()
+                    .position(|c| *c == '"')
+                    .ok_or_else(|| invalid(start, "unterminated phrase"))?;
+                let phrase: String = chars[start + 1..start + 1 + len].iter().collect();
+                if phrase.trim().is_empty() {
+                    return Err(invalid(start, "empty phrase"));
+                }
+                plain = false;
+                clauses.push(Clause::Text {
+                    text: phrase,
+                    excluded: excluded.take().is_some(),
+                });
+                position = start + len + 2;
+                continue;
+            }
+
+            if chars[start] == '-' {
+                if excluded.is_some() || chars.get(start + 1).map_or(true, |c| c.is_whitespace()) {
+                    return Err(invalid(start, "`-` has to be followed by a term"));
+                }
+                plain = false;
+                excluded = Some(start);
+                position += 1;
+                continue;
+            }
+
+            let len = chars[start..]
+                .iter()
+                .position(|c| c.is_whitespace() || *c == '"')
+                .unwrap_or(chars.len() - start);
+            let word: String = chars[start..start + len].iter().collect();
+            position = start + len;
+            match word.as_str() {
+                "AND" | "OR" | "NOT" if excluded.is_some() => {
+                    return Err(invalid(start, &format!("`{}` can't be excluded", word)));
+                }
+                "NOT" => {
+                    plain = false;
+                    excluded = Some(start);
+                }
+                "AND" | "OR" => {
+                    plain = false;
+                    clauses.push(Clause::Operator {
+                        operator: word,
+                        position: start,
+                    });
+                }
+                _ => match word.split_once(':') {
+                    Some((prefix, value)) if PREFIXES.contains(&prefix) => {
+                        if excluded.is_some() {
+                            return Err(invalid(start, "filters can't be excluded"));
+                        }
+                        if value.is_empty() {
+                            return Err(invalid(start, &format!("`{}:` needs a value", prefix)));
+                        }
+                        plain = false;
+                        let filter = filter.get_or_insert_with(SearchFilter::default);
+                        add_filter(filter, prefix, value, start + prefix.chars().count() + 1)?;
+                    }
+                    _ => clauses.push(Clause::Text {
+                        text: word,
+                        excluded: excluded.take().is_some(),
+                    }),
+                },
+            }
+        }
+        if let Some(position) = excluded {
+            let word = if chars[position] == '-' { "-" } else { "NOT" };
+            return Err(invalid(
+                position,
+                &format!("`{}` has to be followed by a term", word),
+            ));
+        }
+
+        for (i, clause) in clauses.iter().enumerate() {
+            if let Clause::Operator { operator, position } = clause {
+                if i == 0 {
+                    let message = format!("`{}` needs a term before it", operator);
+                    return Err(invalid(*position, &message));
+                }
+                if !matches!(clauses.get(i + 1), Some(Clause::Text { .. })) {
+                    let message = format!("`{}` needs a term after it", operator);
+                    return Err(invalid(*position, &message));
+                }
+            }
+        }
+
+        let terms: Vec<String> = clauses
+            .iter()
+            .filter_map(|clause| match clause {
+                Clause::Text {
+                    text,
+                    excluded: false,
+                } => Some(text.clone()),
+                _ => None,
+            })
+            .collect();
+        let text = if plain {
+            query.to_owned()
+        } else {
+            tantivy_query(&clauses, terms.is_empty())
+        };
+
+        Ok(Query {
+            text,
+            terms,
+            filter,
+            plain,
+        })
+    }
+}
+
+const PREFIXES: &[&str] = &["from", "in", "before", "after"];
+
+fn add_filter(filter: &mut SearchFilter, prefix: &str, value: &str, position: usize) -> Result<()> {
+    match prefix {
+        "from" => filter
+            .senders
+            .get_or_insert_with(Vec::new)
+            .push(value.to_owned()),
+        "in" => filter
+            .rooms
+            .get_or_insert_with(Vec::new)
+            .push(value.to_owned()),
+        "before" => {
+            let to_ts = day_start(value, position)? - 1;
+            filter.to_ts = Some(filter.to_ts.map_or(to_ts, |ts| ts.min(to_ts)));
+        }
+        _ => {
+            let from_ts = day_start(value, position)? + DAY_MS;
+            filter.from_ts = Some(filter.from_ts.map_or(from_ts, |ts| ts.max(from_ts)));
+        }
+    }
+    Ok(())
+}
+
+/// Quotes every word so tantivy doesn't mistake `:` and the like for syntax.
+/// Without explicit operators the clauses are left next to each other, the
+/// way tantivy reads a plain term. With them tantivy wants one between every
+/// two clauses, so excluded ones are joined with `AND` and the rest with `OR`.
+fn tantivy_query(clauses: &[Clause], only_excluded: bool) -> String {
+    let operators = clauses
+        .iter()
+        .any(|clause| matches!(clause, Clause::Operator { .. }));
+    let mut text = String::new();
+    let mut after_text = false;
+    // excluding from nothing finds nothing
+    if only_excluded && !clauses.is_empty() {
+        text.push('*');
+        after_text = true;
+    }
+    for clause in clauses {
+        match clause {
+            Clause::Operator { operator, .. } => {
+                text.push(' ');
+                text.push_str(operator);
+                after_text = false;
+            }
+            Clause::Text {
+                text: clause,
+                excluded,
+            } => {
+                if after_text && operators {
+                    text.push_str(if *excluded { " AND" } else { " OR" });
+                }
+                if !text.is_empty() {
+                    text.push(' ');
+                }
+                if *excluded {
+                    text.push('-');
+                }
+                text.push('"');
+                text.push_str(clause);
+                text.push('"');
+                after_text = true;
+            }
+        }
+    }
+    text
+}
+
+/// Milliseconds since the epoch at the start of a `YYYY-MM-DD` day in UTC.
+fn day_start(date: &str, position: usize) -> Result<i64> {
+    let invalid_date = || invalid(position, "expected a date like 2020-01-01");
+    let parts: Vec<&str> = date.split('-').collect();
+    let digits = |part: &str, len| part.len() == len && part.chars().all(|c| c.is_ascii_digit());
+    if parts.len() != 3 || !digits(parts[0], 4) || !digits(parts[1], 2) || !digits(parts[2], 2) {
+        return Err(invalid_date());
+    }
+    let year: i64 = parts[0].parse().map_err(|_| invalid_date())?;
+    let month: i64 = parts[1].parse().map_err(|_| invalid_date())?;
+    let day: i64 = parts[2].parse().map_err(|_| invalid_date())?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid_date()),
+    };
+    if day < 1 || day > days_in_month {
+        return Err(invalid_date());
+    }
+
+    // days from the civil calendar, counting years from march on so that
+    // leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Ok((era * 146_097 + day_of_era - 719_468) * DAY_MS)
+}
+
+fn invalid(position: usize, message: &str) -> Error {
+    Error::InvalidQuery {
+        position,
+        message: message.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(query: &str) -> (usize, String) {
+        match Query::parse(query) {
+            Err(Error::InvalidQuery { position, message }) => (position, message),
+            res => panic!(
+                "{:?} didn't fail to parse: {:?}",
+                query,
+                res.map(|q| q.text)
+            ),
+        }
+    }
+
+    #[test]
+    fn plain() {
+        let query = Query::parse("test message").unwrap();
+        assert!(query.plain);
+        assert_eq!(query.text, "test message");
+        assert_eq!(query.terms, vec!["test", "message"]);
+        assert_eq!(query.filter, None);
+
+        let query = Query::parse("").unwrap();
+        assert!(query.plain);
+        assert_eq!(query.text, "");
+    }
+
+    #[test]
+    fn syntax() {
+        let query = Query::parse("\"exact phrase\" -spam NOT eggs http://x").unwrap();
+        assert!(!query.plain);
+        assert_eq!(
+            query.text,
+            "\"exact phrase\" -\"spam\" -\"eggs\" \"http://x\""
+        );
+        assert_eq!(query.terms, vec!["exact phrase", "http://x"]);
+
+        let query = Query::parse("a b AND c -d OR e").unwrap();
+        assert_eq!(query.text, "\"a\" OR \"b\" AND \"c\" AND -\"d\" OR \"e\"");
+
+        let query = Query::parse("-spam from:@alice:localhost").unwrap();
+        assert_eq!(query.text, "* -\"spam\"");
+        assert!(query.terms.is_empty());
+
+        let query = Query::parse("in:!room:localhost").unwrap();
+        assert_eq!(query.text, "");
+    }
+
+    #[test]
+    fn filters() {
+        let query = Query::parse(
+            "hello from:@alice:localhost from:@bob:localhost in:!room:localhost \
+             after:2019-12-31 before:2020-03-01 before:2020-02-01",
+        )
+        .unwrap();
+        assert_eq!(query.text, "\"hello\"");
+        let filter = query.filter.unwrap();
+        assert_eq!(
+            filter.senders,
+            Some(vec![
+                "@alice:localhost".to_owned(),
+                "@bob:localhost".to_owned()
+            ])
+        );
+        assert_eq!(filter.rooms, Some(vec!["!room:localhost".to_owned()]));
+        // 2020-01-01T00:00:00Z up to 2020-01-31T23:59:59.999Z
+        assert_eq!(filter.from_ts, Some(1_577_836_800_000));
+        assert_eq!(filter.to_ts, Some(1_580_515_199_999));
+
+        let filter = Query::parse("before:1970-01-01 after:2000-02-29")
+            .unwrap()
+            .filter
+            .unwrap();
+        assert_eq!(filter.to_ts, Some(-1));
+        assert_eq!(filter.from_ts, Some(951_868_800_000));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("say \"hi"), (4, "unterminated phrase".to_owned()));
+        assert_eq!(error("\" \""), (0, "empty phrase".to_owned()));
+        assert_eq!(
+            error("spam -"),
+            (5, "`-` has to be followed by a term".to_owned())
+        );
+        assert_eq!(
+            error("spam NOT"),
+            (5, "`NOT` has to be followed by a term".to_owned())
+        );
+        assert_eq!(
+            error("--spam"),
+            (1, "`-` has to be followed by a term".to_owned())
+        );
+        assert_eq!(error("-OR"), (1, "`OR` can't be excluded".to_owned()));
+        assert_eq!(
+            error("AND spam"),
+            (0, "`AND` needs a term before it".to_owned())
+        );
+        assert_eq!(
+            error("a OR OR b"),
+            (2, "`OR` needs a term after it".to_owned())
+        );
+        assert_eq!(
+            error("a AND from:@alice:localhost"),
+            (2, "`AND` needs a term after it".to_owned())
+        );
+        assert_eq!(
+            error("-from:@alice"),
+            (1, "filters can't be excluded".to_owned())
+        );
+        assert_eq!(error("hi in:"), (3, "`in:` needs a value".to_owned()));
+        assert_eq!(
+            error("before:2020-02-30"),
+            (7, "expected a date like 2020-01-01".to_owned())
+        );
+        assert_eq!(
+            error("after:yesterday"),
+            (6, "expected a date like 2020-01-01".to_owned())
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Redactions, i.e. `m.room.redaction` events. They aren't indexed, the event
+//! they redact is deleted instead. Crawling goes back in time and finds the
+//! redaction before the event, so redacted event IDs are remembered for good.
+
+use serde_json::Value;
+use seshat::{Connection, Error as SeshatError};
+
+use crate::error::Result;
+
+/// The ID of the event a redaction redacts, `None` if it isn't a redaction.
+pub fn target(event: &Value) -> Option<&str> {
+    if event["type"] != "m.room.redaction" {
+        return None;
+    }
+    // moved into the content with room version 11
+    event["redacts"]
+        .as_str()
+        .or_else(|| event["content"]["redacts"].as_str())
+}
+
+/// Whether the server already handed out the event redacted.
+pub fn is_redacted(event: &Value) -> bool {
+    !event["unsigned"]["redacted_because"].is_null()
+}
+
+pub fn create_table(connection: &Connection) -> Result<()> {
+    connection
+        .execute_batch(
+            "CREATE TABLE IF NOT EXISTS redacted_events (
+                event_id TEXT NOT NULL PRIMARY KEY
+            )",
+        )
+        .map_err(SeshatError::from)?;
+    Ok(())
+}
+
+/// Returns whether the event wasn't known to be redacted before.
+pub fn remember(connection: &Connection, event_id: &str) -> Result<bool> {
+    let inserted = connection
+        .execute(
+            "INSERT OR IGNORE INTO redacted_events (event_id) VALUES (?1)",
+            &[event_id],
+        )
+        .map_err(SeshatError::from)?;
+    Ok(inserted > 0)
+}
+
+pub fn contains(connection: &Connection, event_id: &str) -> Result<bool> {
+    exists(
+        connection,
+        "SELECT COUNT(*) FROM redacted_events WHERE event_id = ?1",
+        event_id,
+    )
+}
+
+pub fn in_store(connection: &Connection, event_id: &str) -> Result<bool> {
+    exists(
+        connection,
+        "SELECT COUNT(*) FROM events WHERE event_id = ?1",
+        event_id,
+    )
+}
+
+fn exists(connection: &Connection, sql: &str, event_id: &str) -> Result<bool> {
+    let count: i64 = connection
+        .query_row(sql, &[event_id], |row| row.get(0))
+        .map_err(SeshatError::from)?;
+    Ok(count > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redaction_target() {
+        let redaction = json!({ "type": "m.room.redaction", "redacts": "$1" });
+        assert_eq!(target(&redaction), Some("$1"));
+        let redaction = json!({ "type": "m.room.redaction", "content": { "redacts": "$1" } });
+        assert_eq!(target(&redaction), Some("$1"));
+        let message = json!({ "type": "m.room.message", "redacts": "$1" });
+        assert_eq!(target(&message), None);
+    }
+}
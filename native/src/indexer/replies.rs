@@ -0,0 +1,29 @@
+//! Reply fallbacks, i.e. the quote of the replied to message that replies
+//! start their `body` with, `> ` in front of every line and an empty line
+//! after. Indexed as is, search would find every reply to a message as well.
+
+/// The body without the quote of the message replied to.
+pub fn strip_fallback(body: &str) -> String {
+    let mut lines = body.lines().peekable();
+    let mut quoted = false;
+    while lines.next_if(|line| line.starts_with('>')).is_some() {
+        quoted = true;
+    }
+    if quoted {
+        lines.next_if_eq(&"");
+    }
+    lines.collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip() {
+        let body = "> <@alice:localhost> quoted\n> text\n\nthe reply\n> quoting later";
+        assert_eq!(strip_fallback(body), "the reply\n> quoting later");
+        assert_eq!(strip_fallback("no quote\n\nat all"), "no quote\n\nat all");
+        assert_eq!(strip_fallback("> only a quote"), "");
+    }
+}
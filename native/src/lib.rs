@@ -0,0 +1,201 @@
+//! The radical-native host without the stdio loop, so other front ends and
+//! tests can embed it.
+
+use serde_json::{json, Value};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+pub mod cancel;
+pub mod capabilities;
+pub mod dispatcher;
+pub mod error;
+pub mod handler;
+#[cfg(feature = "seshat")]
+pub mod indexer;
+pub mod native_messaging;
+pub mod notifications;
+pub mod secrets;
+
+// the messages of every type, for embedders building requests
+pub use capabilities::Message as HostMessage;
+#[cfg(feature = "seshat")]
+pub use indexer::Message as SeshatMessage;
+pub use secrets::Message as KeytarMessage;
+
+use cancel::CancelToken;
+use error::{Error, Result};
+use handler::Handler;
+use notifications::Notifier;
+
+/// Message types the host answers itself, handlers can't be registered
+/// under them.
+pub const BUILTIN_TYPES: &[&str] = &["host", "cancel"];
+
+/// State of one connection: the registered handlers by message `type`, the
+/// protocol version settled on in `hello` and the notifications subscribed to.
+pub struct Host {
+    handlers: BTreeMap<&'static str, Box<dyn Handler>>,
+    protocol_version: AtomicU32,
+    notifier: Notifier,
+}
+
+/// Registers every handler that was compiled in, with the OS keyring for
+/// pickle keys.
+impl Default for Host {
+    fn default() -> Host {
+        #[allow(unused_mut)]
+        let mut host = Host::new();
+        #[cfg(feature = "seshat")]
+        host.register("seshat", indexer::Seshat::new(host.notifier().clone()));
+        #[cfg(feature = "keytar")]
+        host.register("keytar", secrets::Secrets::new(Box::new(secrets::Keytar)));
+        host
+    }
+}
+
+impl Host {
+    /// A host without any handlers, only answering `host` and `cancel`.
+    pub fn new() -> Host {
+        Host {
+            handlers: BTreeMap::new(),
+            protocol_version: AtomicU32::new(capabilities::MIN_PROTOCOL_VERSION),
+            notifier: Notifier::default(),
+        }
+    }
+
+    /// Routes messages of the given `type` to the handler, replacing any
+    /// handler registered before.
+    pub fn register(&mut self, message_type: &'static str, handler: impl Handler + 'static) {
+        assert!(
+            !BUILTIN_TYPES.contains(&message_type),
+            "{} is handled by the host",
+            message_type
+        );
+        self.handlers.insert(message_type, Box::new(handler));
+    }
+
+    pub fn handlers(&self) -> impl Iterator<Item = (&'static str, &dyn Handler)> {
+        self.handlers
+            .iter()
+            .map(|(message_type, handler)| (*message_type, handler.as_ref()))
+    }
+
+    /// Handles a single message, e.g.
+    /// `{"type": "seshat", "method": "getStats", "eventStore": "default"}`,
+    /// and returns what would be sent back as `reply`.
+    ///
+    /// Messages run one after another, so there's never anything to cancel.
+    pub fn handle(&self, message: Value) -> Result<Value> {
+        self.handle_message(message, &CancelToken::default())
+    }
+
+    pub fn handle_message(&self, message: Value, cancel: &CancelToken) -> Result<Value> {
+        let message_type = message_type(&message)?.to_owned();
+        match message_type.as_str() {
+            "host" => capabilities::handle_message(self, message),
+            "cancel" => Ok(json!(false)),
+            message_type => {
+                let handler = self.handler(message_type)?;
+                // a panic only fails this request, handlers that were left in
+                // a broken state recover on their next request
+                panic::catch_unwind(AssertUnwindSafe(|| handler.handle(message, cancel)))
+                    .unwrap_or_else(|panic| {
+                        let error = format!("{} panicked: {}", message_type, panic_message(&panic));
+                        Err(Error::Internal(error))
+                    })
+            }
+        }
+    }
+
+    /// Lane the message has to run on, see [`Handler::lane`].
+    pub fn lane(&self, message: &Value) -> Result<String> {
+        let lane = match message_type(message)? {
+            // cancel shares the lane with the other quick host messages,
+            // so it's never stuck behind a long running request
+            "host" | "cancel" => "host".to_owned(),
+            message_type => match self.handler(message_type)?.lane(message)? {
+                Some(lane) => format!("{}:{}", message_type, lane),
+                None => message_type.to_owned(),
+            },
+        };
+
+        Ok(lane)
+    }
+
+    /// Shuts down every handler, trying all of them even if some fail.
+    pub fn shutdown(&self) -> Result<()> {
+        let mut failed = Vec::new();
+        for (message_type, handler) in &self.handlers {
+            if let Err(error) = handler.shutdown() {
+                eprintln!("shutting down {} failed: {}", message_type, error);
+                failed.push(error);
+            }
+        }
+
+        match failed.len() {
+            0 => Ok(()),
+            1 => Err(failed.remove(0)),
+            _ => Err(Error::Internal(
+                failed
+                    .iter()
+                    .map(Error::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )),
+        }
+    }
+
+    /// Handlers that push notifications get a clone of this, front ends set
+    /// its sink.
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version.load(Ordering::Relaxed)
+    }
+
+    fn set_protocol_version(&self, version: u32) {
+        self.protocol_version.store(version, Ordering::Relaxed);
+    }
+
+    fn handler(&self, message_type: &str) -> Result<&dyn Handler> {
+        self.handlers
+            .get(message_type)
+            .map(AsRef::as_ref)
+            .ok_or_else(|| Error::UnknownType(message_type.to_owned()))
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Locks a mutex whether or not it's poisoned. Only for locks that are held
+/// just to update maps and write frames, which doesn't panic and can't leave
+/// anything half done.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn message_type(message: &Value) -> Result<&str> {
+    match message.get("type") {
+        Some(res) => res
+            .as_str()
+            .ok_or_else(|| Error::InvalidMessage("type is not a string".to_owned())),
+        None => Err(Error::InvalidMessage("no type given".to_owned())),
+    }
+}
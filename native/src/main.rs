@@ -1,51 +1,90 @@
-use anyhow::anyhow;
-use serde::Deserialize;
-use serde_json::Value;
-use std::collections::HashMap;
+use radical_native::{
+    dispatcher,
+    error::{Error, Result},
+    native_messaging::{set_max_frame_size, set_max_inbound_frame_size},
+};
+use std::{env, io, process};
 
-mod indexer;
-mod native_messaging;
-mod secrets;
+/// Speaks native messaging on stdin/stdout, or with `--listen <path>` the same
+/// protocol on a unix socket, for development tools and tests.
+///
+/// Exits with 0 after the browser closed the pipe and every event store was
+/// shut down cleanly, and with 1 if reading from the pipe broke or shutting
+/// down any of them failed.
+fn main() {
+    if let Some(size) = env_size("RADICAL_NATIVE_MAX_FRAME_SIZE") {
+        set_max_frame_size(size);
+    }
+    if let Some(size) = env_size("RADICAL_NATIVE_MAX_INBOUND_FRAME_SIZE") {
+        set_max_inbound_frame_size(size);
+    }
 
-use indexer::IndexerMap;
-use native_messaging::{stdin, stdout_error, stdout_ready, stdout_reply};
+    // browsers pass the manifest path or extension origin as arguments,
+    // so anything but --listen is ignored
+    let args: Vec<String> = env::args().skip(1).collect();
+    let res = match args.first().map(String::as_str) {
+        Some("--listen") => match args.get(1) {
+            Some(path) => listen(path),
+            None => Err(Error::InvalidMessage(
+                "--listen needs a socket path".to_owned(),
+            )),
+        },
+        _ => dispatcher::serve(io::stdin(), io::stdout()),
+    };
 
-pub struct Radical {
-    indexer: IndexerMap,
+    if let Err(error) = res {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase", tag = "type")]
-pub enum Message {
-    Seshat(Value),
-    Keytar(Value),
+/// Serves clients on a unix socket one after another.
+#[cfg(unix)]
+fn listen(path: &str) -> Result<()> {
+    use std::{
+        fs,
+        os::unix::{fs::FileTypeExt, net::UnixListener},
+    };
+
+    // a socket left behind by an earlier run, anything else is a wrong path
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(Error::InvalidMessage(format!(
+                "{} exists and isn't a socket",
+                path
+            )))
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+        Err(error) => return Err(error.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let writer = stream.try_clone()?;
+        if let Err(error) = dispatcher::serve(stream, writer) {
+            eprintln!("{}", error);
+        }
+    }
+
+    Ok(())
 }
 
-fn main() {
-    let mut radical = Radical {
-        indexer: HashMap::new(),
-    };
+#[cfg(not(unix))]
+fn listen(_path: &str) -> Result<()> {
+    Err(Error::Internal(
+        "--listen is only supported on unix".to_owned(),
+    ))
+}
 
-    stdout_ready();
-    loop {
-        let (rpc_id, message) = match stdin() {
-            Ok(stdin) => stdin,
-            Err(error) => {
-                stdout_error(-1, error);
-                continue;
-            }
-        };
-
-        let message = serde_json::from_value(message);
-        let reply = match message {
-            Ok(Message::Seshat(message)) => indexer::handle_message(&mut radical, message),
-            Ok(Message::Keytar(message)) => secrets::handle_message(message),
-            _ => Err(anyhow!("handling message failed: {:?}", message)),
-        };
-
-        match reply {
-            Ok(reply) => stdout_reply(rpc_id, reply),
-            Err(error) => stdout_error(rpc_id, error),
-        };
+fn env_size(name: &str) -> Option<usize> {
+    let size = env::var(name).ok()?;
+    match size.parse() {
+        Ok(size) => Some(size),
+        Err(error) => {
+            eprintln!("invalid {}: {}", name, error);
+            None
+        }
     }
 }
@@ -1,54 +1,452 @@
-use anyhow::{bail, Context, Error, Result};
 use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
 use serde_json::{json, Value};
-use std::io::{self, prelude::*, Cursor};
-
-pub fn stdin() -> Result<(i64, Value)> {
-    let mut buffer = [0; 4];
-    io::stdin().read_exact(&mut buffer)?;
-    let mut buf = Cursor::new(&buffer);
-    let size = buf.read_u32::<NativeEndian>()?;
-
-    let mut data_buffer = vec![0u8; size as usize];
-    io::stdin().read_exact(&mut data_buffer)?;
-    let message: Value = serde_json::from_slice(&data_buffer)?;
-    let rpc_id = match message.get("rpcId") {
-        Some(res) => res.as_i64().context("invalid rpcId")?,
-        None => bail!("no rpcId given"),
+use std::{
+    io::{self, prelude::*, Cursor, ErrorKind},
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    capabilities::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION},
+    error::{Error, Result},
+    lock,
+};
+
+/// Browsers drop messages from the host that are bigger than 1 MiB.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// Largest message accepted from the browser, crawler batches stay well below.
+pub const DEFAULT_MAX_INBOUND_FRAME_SIZE: usize = 64 * 1024 * 1024;
+/// Frames announcing more than this are taken as a garbled header instead of
+/// being skipped, Chrome doesn't send more than 64 MiB in the first place.
+pub const MAX_SKIPPED_FRAME_SIZE: u64 = 1024 * 1024 * 1024;
+/// How much of a frame that's too big is looked at for its rpcId.
+const RPC_ID_PREFIX_SIZE: u64 = 1024;
+/// First protocol version in which the extension reassembles chunked replies.
+pub const CHUNKED_REPLIES_PROTOCOL_VERSION: u32 = 2;
+
+static MAX_FRAME_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE);
+static MAX_INBOUND_FRAME_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_INBOUND_FRAME_SIZE);
+
+pub fn max_frame_size() -> usize {
+    MAX_FRAME_SIZE.load(Ordering::Relaxed)
+}
+
+pub fn set_max_frame_size(size: usize) {
+    MAX_FRAME_SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn max_inbound_frame_size() -> usize {
+    MAX_INBOUND_FRAME_SIZE.load(Ordering::Relaxed)
+}
+
+pub fn set_max_inbound_frame_size(size: usize) {
+    MAX_INBOUND_FRAME_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// The rpcId of a frame, and the message in it or why there's none.
+pub type Frame = (i64, Result<Value>);
+
+/// Reads the length prefixed JSON frames the browser (or any other client)
+/// sends.
+pub struct FrameReader<R> {
+    reader: R,
+    max_frame_size: usize,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R, max_frame_size: usize) -> FrameReader<R> {
+        FrameReader {
+            reader,
+            max_frame_size,
+        }
+    }
+
+    /// Reads the next message, `None` once the other side closed the stream.
+    /// An error means the stream broke or sent garbage, and there's no point
+    /// in reading on.
+    ///
+    /// Frames that are too big or don't hold a valid message are consumed as
+    /// a whole and returned with their error, so the next call starts at the
+    /// following frame. They come with their rpcId if it can be found, or -1.
+    pub fn read_message(&mut self) -> Result<Option<Frame>> {
+        let mut buffer = [0; 4];
+        if let Err(error) = self.reader.read_exact(&mut buffer) {
+            return closed(error);
+        }
+        let mut buf = Cursor::new(&buffer);
+        let size = u64::from(buf.read_u32::<NativeEndian>()?);
+
+        if size > self.max_frame_size as u64 {
+            if size > MAX_SKIPPED_FRAME_SIZE {
+                return Err(Error::FrameTooLarge(size as usize));
+            }
+            // the extension sends the rpcId first, so the error can be
+            // replied to without reading the whole frame
+            let mut prefix = Vec::new();
+            self.reader
+                .by_ref()
+                .take(size.min(RPC_ID_PREFIX_SIZE))
+                .read_to_end(&mut prefix)?;
+            let rest = size - prefix.len() as u64;
+            let skipped = io::copy(&mut self.reader.by_ref().take(rest), &mut io::sink())?;
+            if skipped < rest {
+                return Ok(None);
+            }
+            let rpc_id = find_rpc_id(&prefix).unwrap_or(-1);
+            return Ok(Some((rpc_id, Err(Error::FrameTooLarge(size as usize)))));
+        }
+
+        let mut data_buffer = Vec::new();
+        self.reader
+            .by_ref()
+            .take(size)
+            .read_to_end(&mut data_buffer)?;
+        if (data_buffer.len() as u64) < size {
+            return Ok(None);
+        }
+
+        Ok(Some(message(&data_buffer)))
+    }
+}
+
+fn message(data: &[u8]) -> Frame {
+    let message: Value = match serde_json::from_slice(data) {
+        Ok(message) => message,
+        Err(error) => return (-1, Err(error.into())),
     };
+    if !message.is_object() {
+        return (-1, Err(Error::InvalidMessage("not an object".to_owned())));
+    }
+    match message.get("rpcId").map(Value::as_i64) {
+        Some(Some(rpc_id)) => (rpc_id, Ok(message)),
+        Some(None) => (-1, Err(Error::InvalidMessage("invalid rpcId".to_owned()))),
+        None => (-1, Err(Error::InvalidMessage("no rpcId given".to_owned()))),
+    }
+}
 
-    Ok((rpc_id, message))
+/// The top level `rpcId` in the beginning of a frame, as long as it isn't
+/// cut off.
+fn find_rpc_id(prefix: &[u8]) -> Option<i64> {
+    let mut depth = 0;
+    let mut position = 0;
+    while position < prefix.len() {
+        match prefix[position] {
+            b'"' => {
+                let start = position + 1;
+                let end = start + string_len(&prefix[start..])?;
+                position = end + 1;
+                let rest = skip_whitespace(&prefix[position..]);
+                if depth != 1 || &prefix[start..end] != b"rpcId" || !rest.starts_with(b":") {
+                    continue;
+                }
+                let number = skip_whitespace(&rest[1..]);
+                let len = number
+                    .iter()
+                    .position(|c| !(c.is_ascii_digit() || *c == b'-'))?;
+                return std::str::from_utf8(&number[..len]).ok()?.parse().ok();
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth -= 1,
+            _ => (),
+        }
+        position += 1;
+    }
+    None
 }
 
-pub fn stdout_ready() {
-    stdout(json!({
-        "ready": true,
-        "version": env!("CARGO_PKG_VERSION").to_owned(),
-    }))
-    .unwrap_or_else(|error| eprintln!("{:?}", error));
+/// Bytes up to the closing quote of a JSON string.
+fn string_len(string: &[u8]) -> Option<usize> {
+    let mut escaped = false;
+    string.iter().position(|c| {
+        let end = !escaped && *c == b'"';
+        escaped = !escaped && *c == b'\\';
+        end
+    })
 }
 
-pub fn stdout_reply(rpc_id: i64, reply: Value) {
-    stdout(json!({
-        "rpcId": rpc_id,
-        "reply": reply,
-    }))
-    .unwrap_or_else(|error| eprintln!("{:?}", error));
+fn skip_whitespace(json: &[u8]) -> &[u8] {
+    let start = json.iter().position(|c| !c.is_ascii_whitespace());
+    &json[start.unwrap_or(json.len())..]
+}
+
+fn closed(error: io::Error) -> Result<Option<Frame>> {
+    match error.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe => Ok(None),
+        _ => Err(error.into()),
+    }
+}
+
+/// Writes length prefixed JSON frames, shared by all threads that reply on
+/// the same connection.
+pub struct FrameWriter {
+    writer: Mutex<Box<dyn Write + Send>>,
+    protocol_version: AtomicU32,
+}
+
+impl FrameWriter {
+    pub fn new(writer: impl Write + Send + 'static) -> FrameWriter {
+        FrameWriter {
+            writer: Mutex::new(Box::new(writer)),
+            protocol_version: AtomicU32::new(MIN_PROTOCOL_VERSION),
+        }
+    }
+
+    /// Protocol version agreed on with the extension in `hello`.
+    pub fn set_protocol_version(&self, version: u32) {
+        self.protocol_version.store(version, Ordering::Relaxed);
+    }
+
+    pub fn ready(&self) {
+        let message = json!({
+            "ready": true,
+            "version": env!("CARGO_PKG_VERSION").to_owned(),
+            "protocolVersion": PROTOCOL_VERSION,
+        });
+        self.write_frames(&[message.to_string()])
+            .unwrap_or_else(|error| eprintln!("{}", error));
+    }
+
+    pub fn reply(&self, rpc_id: i64, reply: Value) {
+        self.send(
+            rpc_id,
+            json!({
+                "rpcId": rpc_id,
+                "reply": reply,
+            }),
+        )
+        .unwrap_or_else(|error| eprintln!("{}", error));
+    }
+
+    pub fn error(&self, rpc_id: i64, error: Error) {
+        self.send(
+            rpc_id,
+            json!({
+                "rpcId": rpc_id,
+                "error": error.to_reply(),
+            }),
+        )
+        .unwrap_or_else(|error| eprintln!("{}", error));
+    }
+
+    /// Pushes a notification, which unlike replies isn't chunked and gets
+    /// dropped if too big.
+    pub fn notify(&self, notification: &str, params: Value) {
+        let message = json!({
+            "notification": notification,
+            "params": params,
+        })
+        .to_string();
+        if message.len() > max_frame_size() {
+            eprintln!(
+                "notification {} too large: {} bytes",
+                notification,
+                message.len()
+            );
+            return;
+        }
+        self.write_frames(&[message])
+            .unwrap_or_else(|error| eprintln!("{}", error));
+    }
+
+    fn send(&self, rpc_id: i64, message: Value) -> Result<()> {
+        let message = serde_json::to_string(&message)?;
+        let max_frame_size = max_frame_size();
+        if message.len() <= max_frame_size {
+            return self.write_frames(&[message]);
+        }
+
+        if self.protocol_version.load(Ordering::Relaxed) < CHUNKED_REPLIES_PROTOCOL_VERSION {
+            // the extension can't reassemble chunks and the browser would drop
+            // the whole connection on a too big frame
+            let error = Error::ReplyTooLarge(message.len());
+            let message = json!({
+                "rpcId": rpc_id,
+                "error": error.to_reply(),
+            });
+            return self.write_frames(&[message.to_string()]);
+        }
+
+        self.write_frames(&chunks(rpc_id, &message, max_frame_size)?)
+    }
+
+    fn write_frames(&self, frames: &[String]) -> Result<()> {
+        // replies come from several worker threads, hold the lock for all
+        // frames so that length prefixes, payloads and chunks can't interleave
+        let mut writer = lock(&self.writer);
+        for frame in frames {
+            let mut size = Vec::default();
+            size.write_u32::<NativeEndian>(frame.len() as u32)?;
+            writer.write_all(&size)?;
+            writer.write_all(frame.as_bytes())?;
+        }
+        Ok(writer.flush()?)
+    }
 }
 
-pub fn stdout_error(rpc_id: i64, error: Error) {
-    stdout(json!({
+/// Splits a serialized message into frames of at most `max_frame_size` bytes.
+///
+/// Each frame is `{"rpcId", "chunk", "chunks", "data"}` with `chunk` counting
+/// from 0 up to `chunks - 1`. Concatenating `data` of all frames in `chunk`
+/// order gives back the original message.
+fn chunks(rpc_id: i64, message: &str, max_frame_size: usize) -> Result<Vec<String>> {
+    // chunk numbers aren't known yet, so account for the widest possible ones
+    let envelope = json!({
         "rpcId": rpc_id,
-        "error": format!("{:?}", error),
-    }))
-    .unwrap_or_else(|error| eprintln!("{:?}", error));
-}
-
-fn stdout(message: Value) -> Result<()> {
-    let message = serde_json::to_string(&message)?;
-    let mut size = Vec::default();
-    size.write_u32::<NativeEndian>(message.len() as u32)?;
-    io::stdout().write_all(&size)?;
-    io::stdout().write_all(&message.into_bytes())?;
-    Ok(io::stdout().flush()?)
+        "chunk": u32::MAX,
+        "chunks": u32::MAX,
+        "data": "",
+    })
+    .to_string()
+    .len();
+    // room for at least one character, whether escaped or multibyte
+    let budget = max_frame_size
+        .checked_sub(envelope)
+        .filter(|budget| *budget >= 4)
+        .ok_or_else(|| Error::Internal("max frame size too small for chunks".to_owned()))?;
+
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (index, c) in message.char_indices() {
+        // serialized JSON has no control characters left, so only quotes and
+        // backslashes grow when embedded as string
+        let escaped = match c {
+            '"' | '\\' => 2,
+            c => c.len_utf8(),
+        };
+        if size + escaped > budget {
+            pieces.push(&message[start..index]);
+            start = index;
+            size = 0;
+        }
+        size += escaped;
+    }
+    pieces.push(&message[start..]);
+
+    let count = pieces.len();
+    let frames = pieces
+        .into_iter()
+        .enumerate()
+        .map(|(chunk, data)| {
+            json!({
+                "rpcId": rpc_id,
+                "chunk": chunk,
+                "chunks": count,
+                "data": data,
+            })
+            .to_string()
+        })
+        .collect();
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(message: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame
+            .write_u32::<NativeEndian>(message.len() as u32)
+            .unwrap();
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    #[test]
+    fn read_frames() {
+        let mut stream = Vec::new();
+        stream.extend(frame(br#"{"rpcId":1,"type":"seshat"}"#));
+        stream.extend(frame(&[b' '; 100]));
+        let mut too_large = br#"{"rpcId": 2, "content": ""#.to_vec();
+        too_large.extend(&[b' '; 100]);
+        stream.extend(frame(&too_large));
+        stream.extend(frame(b"{not json"));
+        stream.extend(frame(b"[1]"));
+        stream.extend(frame(br#"{"rpcId":"2"}"#));
+        stream.extend(frame(br#"{"type":"keytar"}"#));
+        stream.extend(frame(br#"{"rpcId":3}"#));
+        // header claiming 4 GiB, which can only be garbage
+        stream.extend(&[0xff; 4]);
+        stream.extend(br#"{"rpcId":4}"#);
+
+        let mut reader = FrameReader::new(Cursor::new(stream), 64);
+        let (rpc_id, message) = reader.read_message().unwrap().unwrap();
+        assert_eq!(rpc_id, 1);
+        assert_eq!(message.unwrap()["type"], "seshat");
+
+        let errors = [
+            (-1, "frameTooLarge"),
+            (2, "frameTooLarge"),
+            (-1, "decodeError"),
+            (-1, "invalidMessage"),
+            (-1, "invalidMessage"),
+            (-1, "invalidMessage"),
+        ];
+        for (id, code) in errors.iter() {
+            let (rpc_id, message) = reader.read_message().unwrap().unwrap();
+            assert_eq!((rpc_id, message.unwrap_err().code()), (*id, *code));
+        }
+
+        let (rpc_id, _) = reader.read_message().unwrap().unwrap();
+        assert_eq!(rpc_id, 3);
+
+        assert_eq!(reader.read_message().unwrap_err().code(), "frameTooLarge");
+    }
+
+    #[test]
+    fn rpc_id_prefix() {
+        let prefixes: [(&[u8], Option<i64>); 6] = [
+            (br#"{"rpcId":-3,"content":{"#, Some(-3)),
+            (br#"{ "content": {"rpcId": 1}, "rpcId" : 2, "#, Some(2)),
+            (br#"{"body": "\"rpcId\": 1", "rpcId": 2}"#, Some(2)),
+            (br#"{"type": "rpcId", "content": ["#, None),
+            (br#"{"rpcId": 12"#, None),
+            (br#"{"content": {"rpcId": 1, "#, None),
+        ];
+        for (prefix, rpc_id) in prefixes.iter() {
+            assert_eq!(find_rpc_id(prefix), *rpc_id);
+        }
+    }
+
+    #[test]
+    fn read_truncated() {
+        let mut stream = frame(br#"{"rpcId":1}"#);
+        stream.truncate(8);
+        let mut reader = FrameReader::new(Cursor::new(stream), 64);
+        assert!(reader.read_message().unwrap().is_none());
+
+        let mut reader = FrameReader::new(Cursor::new(vec![1, 0]), 64);
+        assert!(reader.read_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn chunked_reply() {
+        let message = json!({
+            "rpcId": 1,
+            "reply": {
+                "body": "\"quoted\" \\ ünïcödé 🦀 ".repeat(100),
+            },
+        })
+        .to_string();
+
+        let frames = chunks(1, &message, 200).expect("chunks");
+        assert!(frames.len() > 1);
+
+        let mut data = String::new();
+        for (index, frame) in frames.iter().enumerate() {
+            assert!(frame.len() <= 200, "frame too big: {}", frame.len());
+            let frame: Value = serde_json::from_str(frame).expect("frame");
+            assert_eq!(frame["rpcId"], 1);
+            assert_eq!(frame["chunk"], index);
+            assert_eq!(frame["chunks"], frames.len());
+            data.push_str(frame["data"].as_str().expect("data"));
+        }
+        assert_eq!(data, message);
+
+        assert!(chunks(1, &message, 10).is_err());
+    }
 }
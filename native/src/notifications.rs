@@ -0,0 +1,95 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use crate::lock;
+
+type Sink = Box<dyn Fn(&str, Value) + Send + Sync>;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscribe {
+    pub notifications: Vec<String>,
+}
+
+/// Pushes notifications the extension subscribed to, as frames without an
+/// rpcId: `{"notification": name, "params": ...}`.
+///
+/// Cheap to clone, all clones share subscriptions and sink. Without a sink
+/// notifications go nowhere.
+#[derive(Clone, Default)]
+pub struct Notifier(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    subscriptions: Mutex<HashSet<String>>,
+    sink: Mutex<Option<Sink>>,
+}
+
+impl Notifier {
+    pub fn set_sink(&self, sink: impl Fn(&str, Value) + Send + Sync + 'static) {
+        *lock(&self.0.sink) = Some(Box::new(sink));
+    }
+
+    pub fn subscribe(&self, notifications: Vec<String>) {
+        lock(&self.0.subscriptions).extend(notifications);
+    }
+
+    pub fn unsubscribe(&self, notifications: &[String]) {
+        let mut subscriptions = lock(&self.0.subscriptions);
+        for notification in notifications {
+            subscriptions.remove(notification);
+        }
+    }
+
+    pub fn subscriptions(&self) -> Vec<String> {
+        let mut subscriptions: Vec<String> = lock(&self.0.subscriptions).iter().cloned().collect();
+        subscriptions.sort();
+        subscriptions
+    }
+
+    pub fn is_subscribed(&self, notification: &str) -> bool {
+        lock(&self.0.subscriptions).contains(notification)
+    }
+
+    /// Sends the notification if the extension subscribed to it.
+    pub fn notify(&self, notification: &str, params: Value) {
+        if !self.is_subscribed(notification) {
+            return;
+        }
+        if let Some(sink) = lock(&self.0.sink).as_ref() {
+            sink(notification, params);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn subscriptions() {
+        let notifier = Notifier::default();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sink = sent.clone();
+        notifier.set_sink(move |notification, params| {
+            sink.lock().unwrap().push((notification.to_owned(), params))
+        });
+
+        notifier.notify("storeHealth", json!(1));
+        notifier.subscribe(vec!["storeHealth".to_owned()]);
+        notifier.clone().notify("storeHealth", json!(2));
+        notifier.notify("commitCompleted", json!(3));
+        notifier.unsubscribe(&["storeHealth".to_owned()]);
+        notifier.notify("storeHealth", json!(4));
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![("storeHealth".to_owned(), json!(2))]
+        );
+    }
+}
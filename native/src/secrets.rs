@@ -1,15 +1,50 @@
-use anyhow::Result;
 use base64::{encode_config, STANDARD_NO_PAD};
-use keytar::{delete_password, get_password, set_password, Password};
 use rand::random;
 use serde::Deserialize;
 use serde_json::{json, Value};
 
+use crate::{cancel::CancelToken, error::Result, handler::Handler};
+
+#[cfg(feature = "keytar")]
+pub use keyring::Keytar;
+
 const SERVICE: &str = "riot.im";
 
+pub const METHODS: &[&str] = &["getPickleKey", "createPickleKey", "destroyPickleKey"];
+
+/// Where pickle keys are kept.
+pub trait SecretBackend: Send + Sync {
+    fn get_password(&self, service: &str, account: &str) -> Result<Option<String>>;
+    fn set_password(&self, service: &str, account: &str, password: &str) -> Result<()>;
+    /// Returns whether there was a password to delete.
+    fn delete_password(&self, service: &str, account: &str) -> Result<bool>;
+}
+
+/// Pickle keys in a [`SecretBackend`], handling `keytar` messages.
+pub struct Secrets {
+    backend: Box<dyn SecretBackend>,
+}
+
+impl Secrets {
+    pub fn new(backend: Box<dyn SecretBackend>) -> Secrets {
+        Secrets { backend }
+    }
+}
+
+impl Handler for Secrets {
+    fn methods(&self) -> &'static [&'static str] {
+        METHODS
+    }
+
+    fn handle(&self, message: Value, _cancel: &CancelToken) -> Result<Value> {
+        handle_message(self.backend.as_ref(), message)
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase", tag = "method")]
-enum Message {
+#[allow(clippy::enum_variant_names)]
+pub enum Message {
     GetPickleKey { content: MessageContent },
     CreatePickleKey { content: MessageContent },
     DestroyPickleKey { content: MessageContent },
@@ -17,30 +52,26 @@ enum Message {
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct MessageContent {
-    user_id: String,
-    device_id: String,
+pub struct MessageContent {
+    pub user_id: String,
+    pub device_id: String,
 }
 
-pub fn handle_message(message_in: Value) -> Result<Value> {
+pub fn handle_message(secrets: &dyn SecretBackend, message_in: Value) -> Result<Value> {
     let message: Message = serde_json::from_value(message_in)?;
     let res = match message {
         Message::GetPickleKey { content } => {
-            let Password { success, password } =
-                get_password(SERVICE, &account_string(content.user_id, content.device_id))?;
-
-            if success {
-                json!(password)
-            } else {
-                json!(null)
-            }
+            let password = secrets
+                .get_password(SERVICE, &account_string(content.user_id, content.device_id))?;
+
+            json!(password)
         }
         Message::CreatePickleKey { content } => {
-            let random_vec: Vec<u8> = (0..32).into_iter().map(|_| random::<u8>()).collect();
+            let random_vec: Vec<u8> = (0..32).map(|_| random::<u8>()).collect();
             let pickle_key = encode_config(&random_vec, STANDARD_NO_PAD);
             let res = json!(pickle_key);
 
-            set_password(
+            secrets.set_password(
                 SERVICE,
                 &account_string(content.user_id, content.device_id),
                 &pickle_key,
@@ -49,8 +80,8 @@ pub fn handle_message(message_in: Value) -> Result<Value> {
             res
         }
         Message::DestroyPickleKey { content } => {
-            let success =
-                delete_password(SERVICE, &account_string(content.user_id, content.device_id))?;
+            let success = secrets
+                .delete_password(SERVICE, &account_string(content.user_id, content.device_id))?;
 
             json!(success)
         }
@@ -62,3 +93,43 @@ pub fn handle_message(message_in: Value) -> Result<Value> {
 fn account_string(user_id: String, device_id: String) -> String {
     format!("{}|{}", user_id, device_id)
 }
+
+#[cfg(feature = "keytar")]
+mod keyring {
+    use std::fmt::Display;
+
+    use super::SecretBackend;
+    use crate::error::{Error, Result};
+
+    /// The OS keyring: libsecret, the macOS keychain or the Windows credential
+    /// manager.
+    pub struct Keytar;
+
+    impl SecretBackend for Keytar {
+        fn get_password(&self, service: &str, account: &str) -> Result<Option<String>> {
+            let keytar::Password { success, password } =
+                keytar::get_password(service, account).map_err(keyring_error)?;
+
+            Ok(if success { Some(password) } else { None })
+        }
+
+        fn set_password(&self, service: &str, account: &str, password: &str) -> Result<()> {
+            keytar::set_password(service, account, password).map_err(keyring_error)
+        }
+
+        fn delete_password(&self, service: &str, account: &str) -> Result<bool> {
+            keytar::delete_password(service, account).map_err(keyring_error)
+        }
+    }
+
+    // keytar only gives us the platform's error text, libsecret and the macOS
+    // keychain both mention the lock in it
+    fn keyring_error(error: impl Display) -> Error {
+        let message = error.to_string();
+        if message.to_lowercase().contains("locked") {
+            Error::KeyringLocked(message)
+        } else {
+            Error::Keyring(message)
+        }
+    }
+}
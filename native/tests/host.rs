@@ -0,0 +1,243 @@
+use radical_native::{
+    cancel::CancelToken,
+    error::Result,
+    handler::Handler,
+    secrets::{SecretBackend, Secrets},
+    Host,
+};
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Mutex};
+#[cfg(feature = "seshat")]
+use std::{thread, time::Duration};
+
+#[derive(Default)]
+struct MemorySecrets(Mutex<HashMap<String, String>>);
+
+impl SecretBackend for MemorySecrets {
+    fn get_password(&self, service: &str, account: &str) -> Result<Option<String>> {
+        let key = format!("{}/{}", service, account);
+        Ok(self.0.lock().unwrap().get(&key).cloned())
+    }
+
+    fn set_password(&self, service: &str, account: &str, password: &str) -> Result<()> {
+        let key = format!("{}/{}", service, account);
+        self.0.lock().unwrap().insert(key, password.to_owned());
+        Ok(())
+    }
+
+    fn delete_password(&self, service: &str, account: &str) -> Result<bool> {
+        let key = format!("{}/{}", service, account);
+        Ok(self.0.lock().unwrap().remove(&key).is_some())
+    }
+}
+
+/// Asks for stats again while tantivy reloads after a commit, which can make
+/// them fail for a moment.
+#[cfg(feature = "seshat")]
+fn stats(host: &Host) -> Value {
+    for _ in 0..10 {
+        match host.handle(json!({ "type": "seshat", "method": "getStats" })) {
+            Err(error) if error.code() == "seshatError" => thread::sleep(Duration::from_millis(10)),
+            res => return res.expect("getStats"),
+        }
+    }
+    panic!("stats kept failing");
+}
+
+#[cfg(feature = "seshat")]
+fn event_room_message_text() -> Value {
+    json!({
+        "type": "m.room.message",
+        "room_id": "!FDVbSkWZSIcwvBFMdt:localhost",
+        "sender": "@example2:localhost",
+        "content": {
+            "body": "Test message",
+            "msgtype": "m.text"
+        },
+        "origin_server_ts": 1_580_728_702_628_usize,
+        "unsigned": {
+            "age": 949_499_816_usize
+        },
+        "event_id": "$lp49H7iDTNWQxD-fiZ6sDE6vT70DlYdKdoujEB5QtLM",
+        "user_id": "@example2:localhost",
+        "age": 949_499_816_usize
+    })
+}
+
+#[cfg(feature = "seshat")]
+fn profile() -> Value {
+    json!({
+        "displayname": "Alice",
+        "avatar_url": ""
+    })
+}
+
+#[cfg(feature = "seshat")]
+#[test]
+fn json_messages() {
+    let tmpdir = tempfile::tempdir().expect("tempdir");
+    // make sure that we have only one test that modifies the environment
+    // since tests run in parallel
+    std::env::set_var("HOME", tmpdir.path().to_str().expect("tmpdir path"));
+    let host = Host::default();
+    host.handle(json!({
+        "type": "seshat",
+        "method": "initEventIndex"
+    }))
+    .expect("initEventIndex");
+
+    host.handle(json!({
+        "type": "seshat",
+        "method": "addEventToIndex",
+        "content": {
+            "ev": event_room_message_text(),
+            "profile": profile()
+        }
+    }))
+    .expect("addEventToIndex");
+
+    host.handle(json!({
+        "type": "seshat",
+        "method": "commitLiveEvents"
+    }))
+    .expect("commitLiveEvents");
+
+    let checkpoint = json!({
+        "roomId": "!FDVbSkWZSIcwvBFMdt:localhost",
+        "token": "123",
+        "direction": "b"
+    });
+    host.handle(json!({
+        "type": "seshat",
+        "method": "addCrawlerCheckpoint",
+        "content": {
+            "checkpoint": checkpoint
+        }
+    }))
+    .expect("addCrawlerCheckpoint");
+
+    host.handle(json!({
+        "type": "seshat",
+        "method": "removeCrawlerCheckpoint",
+        "content": {
+            "oldCheckpoint": checkpoint
+        }
+    }))
+    .expect("removeCrawlerCheckpoint");
+
+    let checkpoints = host
+        .handle(json!({
+            "type": "seshat",
+            "method": "loadCheckpoints"
+        }))
+        .expect("loadCheckpoints");
+
+    let reply = stats(&host);
+
+    assert_eq!(checkpoints.as_array().expect("checkpoints").len(), 0);
+    assert_eq!(reply["eventCount"].as_i64().expect("eventCount"), 1);
+
+    let mut event = event_room_message_text();
+    event["event_id"] = json!("$uncommitted");
+    host.handle(json!({
+        "type": "seshat",
+        "method": "addEventToIndex",
+        "content": {
+            "ev": event,
+            "profile": profile()
+        }
+    }))
+    .expect("addEventToIndex");
+
+    host.shutdown().expect("shutdown");
+    let error = host
+        .handle(json!({
+            "type": "seshat",
+            "method": "getStats"
+        }))
+        .unwrap_err();
+    assert_eq!(error.code(), "indexNotInitialized");
+
+    host.handle(json!({
+        "type": "seshat",
+        "method": "initEventIndex"
+    }))
+    .expect("initEventIndex");
+
+    let reply = stats(&host);
+    assert_eq!(reply["eventCount"].as_i64().expect("eventCount"), 2);
+}
+
+#[test]
+fn pickle_keys() {
+    let mut host = Host::new();
+    host.register("keytar", Secrets::new(Box::new(MemorySecrets::default())));
+    let content = json!({
+        "userId": "@alice:localhost",
+        "deviceId": "DEVICE"
+    });
+    let message = |method: &str| {
+        json!({
+            "type": "keytar",
+            "method": method,
+            "content": content
+        })
+    };
+
+    let key = host
+        .handle(message("createPickleKey"))
+        .expect("createPickleKey");
+    assert!(key.is_string());
+    assert_eq!(host.handle(message("getPickleKey")).unwrap(), key);
+
+    assert_eq!(host.handle(message("destroyPickleKey")).unwrap(), true);
+    assert_eq!(host.handle(message("getPickleKey")).unwrap(), Value::Null);
+    assert_eq!(host.handle(message("destroyPickleKey")).unwrap(), false);
+}
+
+#[test]
+fn unknown_type() {
+    let host = Host::new();
+    let error = host
+        .handle(json!({
+            "type": "seshat",
+            "method": "getStats"
+        }))
+        .unwrap_err();
+    assert_eq!(error.code(), "unknownType");
+
+    let reply = host
+        .handle(json!({
+            "type": "host",
+            "method": "getCapabilities"
+        }))
+        .expect("getCapabilities");
+    assert!(reply["types"].get("seshat").is_none());
+}
+
+struct Panicking;
+
+impl Handler for Panicking {
+    fn methods(&self) -> &'static [&'static str] {
+        &["panic"]
+    }
+
+    fn handle(&self, _message: Value, _cancel: &CancelToken) -> Result<Value> {
+        panic!("bad event");
+    }
+}
+
+#[test]
+fn handler_panic() {
+    let mut host = Host::new();
+    host.register("panicking", Panicking);
+
+    let error = host
+        .handle(json!({
+            "type": "panicking",
+            "method": "panic"
+        }))
+        .unwrap_err();
+    assert_eq!(error.code(), "internalError");
+    assert!(error.to_string().contains("bad event"));
+}
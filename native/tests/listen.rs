@@ -0,0 +1,241 @@
+#![cfg(all(unix, feature = "seshat"))]
+
+use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use serde_json::{json, Value};
+use std::{
+    io::{Read, Write},
+    iter,
+    os::unix::net::UnixStream,
+    path::Path,
+    process::{Child, Command},
+    thread,
+    time::Duration,
+};
+
+struct Host(Child);
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn send(stream: &mut UnixStream, message: Value) {
+    let message = message.to_string();
+    stream
+        .write_u32::<NativeEndian>(message.len() as u32)
+        .unwrap();
+    stream.write_all(message.as_bytes()).unwrap();
+}
+
+fn receive(stream: &mut UnixStream) -> Value {
+    let size = stream.read_u32::<NativeEndian>().unwrap();
+    let mut message = vec![0; size as usize];
+    stream.read_exact(&mut message).unwrap();
+    serde_json::from_slice(&message).unwrap()
+}
+
+fn connect(path: &Path) -> UnixStream {
+    for _ in 0..100 {
+        if let Ok(stream) = UnixStream::connect(path) {
+            return stream;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("host didn't listen on {:?}", path);
+}
+
+const SOCKET: &str = "radical-native.sock";
+
+/// Starts the host with its data in `dir` and connects to it.
+fn spawn_host(dir: &Path) -> (Host, UnixStream) {
+    let path = dir.join(SOCKET);
+    let host = Host(
+        Command::new(env!("CARGO_BIN_EXE_radical-native"))
+            .arg("--listen")
+            .arg(&path)
+            .env("HOME", dir)
+            .spawn()
+            .unwrap(),
+    );
+    (host, connect(&path))
+}
+
+#[test]
+fn listen() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (_host, stream) = spawn_host(tmpdir.path());
+
+    // connections are served one after another, each with its own state
+    let second = || connect(&tmpdir.path().join(SOCKET));
+    for mut stream in iter::once(stream).chain(iter::once_with(second)) {
+        assert_eq!(receive(&mut stream)["ready"], true);
+
+        send(
+            &mut stream,
+            json!({"rpcId": 1, "type": "host", "method": "getCapabilities"}),
+        );
+        let reply = receive(&mut stream);
+        assert_eq!(reply["rpcId"], 1);
+        assert!(reply["reply"]["types"]["seshat"].is_array());
+
+        send(
+            &mut stream,
+            json!({"rpcId": 2, "type": "seshat", "method": "getStats", "eventStore": "test"}),
+        );
+        let reply = receive(&mut stream);
+        assert_eq!(reply["rpcId"], 2);
+        assert_eq!(reply["error"]["code"], "indexNotInitialized");
+    }
+}
+
+#[test]
+fn listen_path_taken() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join(SOCKET);
+    std::fs::write(&path, "not a socket").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_radical-native"))
+        .arg("--listen")
+        .arg(&path)
+        .env("HOME", tmpdir.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+}
+
+#[test]
+fn notifications() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (_host, mut stream) = spawn_host(tmpdir.path());
+    assert_eq!(receive(&mut stream)["ready"], true);
+
+    send(
+        &mut stream,
+        json!({"rpcId": 1, "type": "host", "method": "subscribe",
+               "content": {"notifications": ["unknown"]}}),
+    );
+    assert_eq!(receive(&mut stream)["error"]["code"], "invalidMessage");
+
+    send(
+        &mut stream,
+        json!({"rpcId": 2, "type": "host", "method": "subscribe",
+               "content": {"notifications": ["storeHealth", "commitCompleted"]}}),
+    );
+    assert_eq!(
+        receive(&mut stream)["reply"],
+        json!(["commitCompleted", "storeHealth"])
+    );
+
+    send(
+        &mut stream,
+        json!({"rpcId": 3, "type": "seshat", "method": "initEventIndex", "eventStore": "test"}),
+    );
+    let notification = receive(&mut stream);
+    assert!(notification.get("rpcId").is_none());
+    assert_eq!(notification["notification"], "storeHealth");
+    assert_eq!(
+        notification["params"],
+        json!({"eventStore": "test", "status": "opened", "error": null})
+    );
+    assert_eq!(receive(&mut stream)["rpcId"], 3);
+
+    send(
+        &mut stream,
+        json!({"rpcId": 4, "type": "seshat", "method": "commitLiveEvents", "eventStore": "test"}),
+    );
+    assert_eq!(receive(&mut stream)["notification"], "commitCompleted");
+    assert_eq!(receive(&mut stream)["rpcId"], 4);
+}
+
+/// Receives frames up to and including the first one matching `last`.
+fn receive_until(stream: &mut UnixStream, last: impl Fn(&Value) -> bool) -> Vec<Value> {
+    let mut frames = Vec::new();
+    loop {
+        let frame = receive(stream);
+        let done = last(&frame);
+        frames.push(frame);
+        if done {
+            return frames;
+        }
+    }
+}
+
+#[test]
+fn set_index_language() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (_host, mut stream) = spawn_host(tmpdir.path());
+    assert_eq!(receive(&mut stream)["ready"], true);
+    let opened = |frame: &Value| frame["params"]["status"] == "opened";
+    let search = json!({"type": "seshat", "method": "searchEventIndex",
+                        "content": {"term": "message", "config": {}}});
+    let mut rpc_id = 0;
+    let mut request = |stream: &mut UnixStream, mut message: Value| {
+        rpc_id += 1;
+        message["rpcId"] = json!(rpc_id);
+        send(stream, message);
+        let id = rpc_id;
+        receive_until(stream, move |frame| frame["rpcId"] == id)
+            .pop()
+            .unwrap()
+    };
+
+    request(
+        &mut stream,
+        json!({"type": "host", "method": "subscribe",
+               "content": {"notifications": ["storeHealth"]}}),
+    );
+    request(
+        &mut stream,
+        json!({"type": "seshat", "method": "initEventIndex"}),
+    );
+    request(
+        &mut stream,
+        json!({"type": "seshat", "method": "addEventToIndex", "content": {
+            "ev": {
+                "type": "m.room.message",
+                "room_id": "!FDVbSkWZSIcwvBFMdt:localhost",
+                "sender": "@example2:localhost",
+                "content": {"body": "Test messages", "msgtype": "m.text"},
+                "origin_server_ts": 1_580_728_702_628_i64,
+                "event_id": "$lp49H7iDTNWQxD-fiZ6sDE6vT70DlYdKdoujEB5QtLM"
+            },
+            "profile": {"displayname": "Alice", "avatar_url": ""}
+        }}),
+    );
+    request(
+        &mut stream,
+        json!({"type": "seshat", "method": "commitLiveEvents"}),
+    );
+    // no stemming without a language, and no user version that would keep
+    // the events when seshat needs a reindex itself
+    assert_eq!(request(&mut stream, search.clone())["reply"]["count"], 0);
+
+    send(
+        &mut stream,
+        json!({"rpcId": 100, "type": "seshat", "method": "setIndexLanguage",
+               "content": {"language": "english"}}),
+    );
+    // the reindex might be done before the reply is sent
+    let mut frames = receive_until(&mut stream, |frame| frame["rpcId"] == 100);
+    assert_eq!(frames.pop().unwrap()["reply"]["status"], "reindexing");
+    if !frames.iter().any(opened) {
+        receive_until(&mut stream, opened);
+    }
+    let reply = request(&mut stream, search.clone());
+    assert_eq!(reply["reply"]["count"], 1);
+    assert_eq!(reply["reply"]["highlights"], json!(["message", "messages"]));
+
+    // the language sticks with the event store
+    request(
+        &mut stream,
+        json!({"type": "seshat", "method": "closeEventIndex"}),
+    );
+    request(
+        &mut stream,
+        json!({"type": "seshat", "method": "initEventIndex"}),
+    );
+    assert_eq!(request(&mut stream, search)["reply"]["count"], 1);
+}
@@ -1,6 +1,9 @@
 import { debug } from "./debug";
 import { Background } from "./lib";
 
+// newest protocol version this extension speaks, see `hello` in the host
+const PROTOCOL_VERSION = 3;
+
 export class NativePort {
   private name =
     process.env.NODE_ENV === "development"
@@ -9,6 +12,15 @@ export class NativePort {
   private port?: browser.runtime.Port;
   private rpcPromises: Map<number, any> = new Map();
   private ready = false;
+  private capabilities?: any;
+  // partially received chunked replies, keyed by rpcId
+  private chunks: Map<number, string[]> = new Map();
+  // ids for the extension's own requests, kept negative so they can't
+  // collide with the ones coming from riot
+  private hostRpcId = -1;
+  // listeners for notifications the host pushes, by notification name
+  private notificationListeners: Map<string, Set<(params: any) => void>> =
+    new Map();
   private bg: Background;
 
   constructor(bg: Background) {
@@ -32,15 +44,17 @@ export class NativePort {
       }
     }
 
-    // construct native message from runtime message
-    const message = runtimeMessage.content;
+    // construct native message from runtime message, rpcId first so the host
+    // can still reply to frames that are too big for it to read
+    const message = { rpcId: runtimeMessage.rpcId, ...runtimeMessage.content };
     message.type = runtimeMessage.type;
     message.rpcId = runtimeMessage.rpcId;
 
     switch (message.type) {
       case "seshat":
         if (message.method === "supportsEventIndexing") {
-          return true;
+          // hosts without a hello handshake always had seshat
+          return this.capabilities ? "seshat" in this.capabilities.types : true;
         }
 
         const url = new URL(sender.url!);
@@ -58,6 +72,41 @@ export class NativePort {
     return this.postMessage(message);
   }
 
+  // subscribes with the host, again after every reconnect
+  addNotificationListener(
+    notification: string,
+    listener: (params: any) => void
+  ): void {
+    const listeners = this.notificationListeners.get(notification) ?? new Set();
+    listeners.add(listener);
+    this.notificationListeners.set(notification, listeners);
+    if (this.capabilities) {
+      this.subscribe([notification]);
+    }
+  }
+
+  private async subscribe(notifications: string[]): Promise<void> {
+    if (!this.capabilities?.notifications) {
+      return;
+    }
+    notifications = notifications.filter((notification) =>
+      this.capabilities.notifications.includes(notification)
+    );
+    if (!notifications.length) {
+      return;
+    }
+    try {
+      await this.postMessage({
+        type: "host",
+        method: "subscribe",
+        rpcId: --this.hostRpcId,
+        content: { notifications },
+      });
+    } catch (error) {
+      debug("subscribing failed", error);
+    }
+  }
+
   private init(): void {
     this.port = browser.runtime.connectNative(this.name);
     this.port.onDisconnect.addListener(this.handleDisconnect.bind(this));
@@ -66,12 +115,14 @@ export class NativePort {
 
   private close(): void {
     this.ready = false;
+    delete this.capabilities;
+    this.chunks.clear();
     this.port?.onDisconnect.removeListener(this.handleDisconnect.bind(this));
     this.port?.onMessage.removeListener(this.handleMessage.bind(this));
     delete this.port;
   }
 
-  private postMessage(message: any): Promise<void> {
+  private postMessage(message: any): Promise<any> {
     return new Promise((resolve, reject) => {
       this.rpcPromises.set(message.rpcId, {
         message,
@@ -83,13 +134,63 @@ export class NativePort {
     });
   }
 
+  private async hello(): Promise<void> {
+    try {
+      this.capabilities = await this.postMessage({
+        type: "host",
+        method: "hello",
+        rpcId: --this.hostRpcId,
+        content: { protocolVersion: PROTOCOL_VERSION },
+      });
+      debug("host capabilities", this.capabilities);
+      this.subscribe([...this.notificationListeners.keys()]);
+    } catch (error) {
+      // keep talking with the defaults of protocol version 1
+      debug("hello failed, assuming defaults", error);
+    }
+  }
+
+  // replies bigger than the host's maxFrameSize arrive as
+  // `{rpcId, chunk, chunks, data}` frames, concatenating `data` in `chunk`
+  // order gives back the original reply
+  private reassemble(message: any): any | undefined {
+    const chunks = this.chunks.get(message.rpcId) ?? [];
+    chunks[message.chunk] = message.data;
+    this.chunks.set(message.rpcId, chunks);
+
+    if (Object.keys(chunks).length < message.chunks) {
+      return;
+    }
+    this.chunks.delete(message.rpcId);
+    return JSON.parse(chunks.join(""));
+  }
+
   private handleMessage(message: any): void {
+    if (message.chunks !== undefined) {
+      message = this.reassemble(message);
+      if (!message) {
+        return;
+      }
+    }
+
+    // notifications come without rpcId as `{notification, params}`
+    if (message.notification !== undefined) {
+      debug("port notification received", message);
+      this.notificationListeners
+        .get(message.notification)
+        ?.forEach((listener) => listener(message.params));
+      return;
+    }
+
     if (message.ready) {
       debug("port ready");
       browser.browserAction.enable();
       browser.browserAction.setTitle({ title: "Radical Native" });
       browser.browserAction.setBadgeText({ text: null });
       this.ready = true;
+      if (message.protocolVersion) {
+        this.hello();
+      }
       return;
     }
 
@@ -110,7 +211,11 @@ export class NativePort {
         error: message.error,
         origExternalMessage: rpcPromise.message,
       });
-      rpcPromise.reject(new Error(message.error));
+      // errors are `{code, message, data}`, older hosts sent plain strings
+      const error: any = new Error(message.error.message ?? message.error);
+      error.code = message.error.code;
+      error.data = message.error.data;
+      rpcPromise.reject(error);
     }
     this.rpcPromises.delete(message.rpcId);
   }